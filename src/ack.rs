@@ -0,0 +1,110 @@
+use log::{error, info, warn};
+use matrix_sdk::{
+    Room,
+    ruma::{
+        api::client::error::ErrorKind,
+        events::{
+            reaction::ReactionEventContent,
+            relation::{Annotation, Thread},
+            room::message::{Relation, RoomMessageEventContent, RoomMessageEventContentWithoutRelation},
+        },
+        OwnedEventId, OwnedRoomId, OwnedUserId, UserId,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// What to do instead of reacting in rooms where the bot is not allowed to send reactions
+pub enum AckFallback {
+    /// Reply with a notice in a thread on the ping, at most once per sender and room per interval
+    ThreadedNotice {
+        text: String,
+        min_interval: Duration,
+    },
+    /// Don't acknowledge the ping at all
+    Nothing,
+}
+
+/// Acknowledges reported pings in the watched room, preferably via reaction
+pub struct Acknowledger {
+    fallback: AckFallback,
+    /// Rooms in which sending the ack reaction was forbidden before
+    reaction_forbidden: Mutex<HashSet<OwnedRoomId>>,
+    /// When we last sent a notice to a sender in a watched room
+    last_notice: Mutex<HashMap<(OwnedRoomId, OwnedUserId), Instant>>,
+}
+
+impl Acknowledger {
+    pub fn new(fallback: AckFallback) -> Self {
+        Acknowledger {
+            fallback,
+            reaction_forbidden: Mutex::new(HashSet::new()),
+            last_notice: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Signal in the watched room that the ping was reported. `relates_to` is the relation of the
+    /// ping, needed to find the right thread root if the ping itself was sent in a thread.
+    pub async fn ack(&self, room: &Room, sender: &UserId, event_id: OwnedEventId, relates_to: Option<&Relation<RoomMessageEventContentWithoutRelation>>) {
+        if !self.is_reaction_forbidden(room) {
+            let reaction = ReactionEventContent::new(
+                Annotation::new(
+                    event_id.clone(),
+                    "📨".to_owned(),
+                )
+            );
+            match room.send(reaction).await {
+                Ok(_) => return,
+                Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::Forbidden { .. })) => {
+                    warn!("Not allowed to send ack reactions in {}, using the configured fallback from now on: {e}", room.room_id());
+                    self.reaction_forbidden.lock().unwrap().insert(room.room_id().to_owned());
+                }
+                Err(e) => {
+                    error!("Failed to send ack reaction: {}", e);
+                    return;
+                }
+            }
+        }
+
+        match &self.fallback {
+            AckFallback::Nothing => {}
+            AckFallback::ThreadedNotice { text, min_interval } => {
+                if !self.may_send_notice(room, sender, *min_interval) {
+                    info!("Already sent an ack notice to {sender} in {} recently, not sending another one", room.room_id());
+                    return;
+                }
+                // Threads can't be nested, so join the existing thread if there is one
+                let thread_root = match relates_to {
+                    Some(Relation::Thread(thread)) => thread.event_id.clone(),
+                    _ => event_id.clone(),
+                };
+                let mut content = RoomMessageEventContent::notice_plain(text);
+                content.relates_to = Some(Relation::Thread(Thread::plain(thread_root, event_id)));
+                if let Err(e) = room.send(content).await {
+                    error!("Failed to send ack notice in {}: {e}", room.room_id());
+                } else {
+                    info!("Sent ack notice in {} instead of a reaction", room.room_id());
+                }
+            }
+        }
+    }
+
+    /// Check whether the notice rate limit allows another notice, and count it if so
+    fn may_send_notice(&self, room: &Room, sender: &UserId, min_interval: Duration) -> bool {
+        let mut last_notice = self.last_notice.lock().unwrap();
+        last_notice.retain(|_, sent| sent.elapsed() < min_interval);
+        let key = (room.room_id().to_owned(), sender.to_owned());
+        if last_notice.contains_key(&key) {
+            return false;
+        }
+        last_notice.insert(key, Instant::now());
+        true
+    }
+
+    fn is_reaction_forbidden(&self, room: &Room) -> bool {
+        self.reaction_forbidden.lock().unwrap().contains(room.room_id())
+    }
+}
@@ -0,0 +1,111 @@
+use config::Config;
+use log::{error, info};
+use matrix_sdk::{
+    Client,
+    ruma::{
+        events::{room::message::RoomMessageEventContent, Mentions},
+        OwnedRoomId, OwnedUserId, RoomId, UserId,
+    },
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::oneshot;
+
+use crate::{notify_report_rooms, telemetry, BotContext};
+
+const CANARY_MARKER: &str = "canary-self-test-";
+
+/// Periodically pings the bot from itself in a watched test room, and alerts if that ping
+/// does not make it into a report
+pub struct Canary {
+    room_id: OwnedRoomId,
+    interval: Duration,
+    timeout: Duration,
+    pending: Mutex<HashMap<String, oneshot::Sender<()>>>,
+}
+
+impl Canary {
+    /// Read the optional `canary` config section, returns None if no canary room is configured
+    pub fn from_config(config: &Config, watched_test_rooms: &[OwnedRoomId]) -> anyhow::Result<Option<Self>> {
+        let Ok(room_id) = config.get::<String>("canary.room") else {
+            return Ok(None);
+        };
+        let room_id = RoomId::parse(room_id)?;
+        // The canary must not ping anyone, neither in the watched room nor in the report rooms
+        if !watched_test_rooms.contains(&room_id) {
+            anyhow::bail!("canary.room {room_id} needs to be one of bot.watched_test_rooms");
+        }
+        Ok(Some(Canary {
+            room_id,
+            interval: Duration::from_secs(config.get::<u64>("canary.interval_secs").unwrap_or(86400)),
+            timeout: Duration::from_secs(config.get::<u64>("canary.timeout_secs").unwrap_or(300)),
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Whether a message sent by the bot itself is a canary, which should be handled like a
+    /// ping by someone else
+    pub fn is_canary(&self, room_id: &RoomId, sender: &UserId, own_user_id: &UserId, body: &str) -> bool {
+        sender == own_user_id && room_id == self.room_id && body.contains(CANARY_MARKER)
+    }
+
+    /// Called once a ping was reported, to resolve the pending canary it belongs to, if any
+    pub fn reported(&self, body: &str) {
+        let Some(nonce) = nonce_of(body) else {
+            return;
+        };
+        if let Some(waiter) = self.pending.lock().unwrap().remove(nonce) {
+            let _ = waiter.send(());
+        }
+    }
+
+    pub async fn run(&self, client: Client, bot_context: BotContext, bot_mxid: OwnedUserId) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            if let Err(reason) = self.test(&client, &bot_mxid).await {
+                error!("Canary self-test failed: {reason}");
+                telemetry::capture_error(&format!("Canary self-test failed: {reason}"), Some(&self.room_id), None);
+                let msg = format!("Canary self-test failed: {reason}. Pings may currently not get reported!");
+                notify_report_rooms(&client, &bot_context, &msg, true).await;
+            }
+        }
+    }
+
+    async fn test(&self, client: &Client, bot_mxid: &UserId) -> Result<(), String> {
+        let room = client.get_room(&self.room_id)
+            .ok_or_else(|| format!("canary room {} is unknown to my client", self.room_id))?;
+        let nonce = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(nonce.clone(), sender);
+
+        // Sent as text rather than notice, as only text and media messages get reported
+        let content = RoomMessageEventContent::text_plain(format!(
+            "{CANARY_MARKER}{nonce}: testing that pings of {bot_mxid} get reported"
+        )).add_mentions(Mentions::with_user_ids([bot_mxid.to_owned()]));
+        if let Err(e) = room.send(content).await {
+            self.pending.lock().unwrap().remove(&nonce);
+            return Err(format!("failed to send canary message: {e}"));
+        }
+
+        let result = tokio::time::timeout(self.timeout, receiver).await;
+        self.pending.lock().unwrap().remove(&nonce);
+        match result {
+            Ok(Ok(())) => {
+                info!("Canary self-test {nonce} passed");
+                Ok(())
+            }
+            _ => Err(format!("canary ping was not reported within {} seconds", self.timeout.as_secs())),
+        }
+    }
+}
+
+fn nonce_of(body: &str) -> Option<&str> {
+    let start = body.find(CANARY_MARKER)? + CANARY_MARKER.len();
+    let rest = &body[start..];
+    let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    Some(&rest[..end])
+}
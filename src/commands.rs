@@ -0,0 +1,232 @@
+use config::{Config, ConfigError};
+use log::{error, info};
+use matrix_sdk::{
+    Client, Room, RoomState,
+    event_handler::Ctx,
+    ruma::{
+        events::{
+            room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+        },
+        matrix_uri::MatrixId,
+        MatrixToUri, MatrixUri, OwnedEventId, OwnedRoomId, OwnedRoomOrAliasId, RoomId,
+    },
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+use crate::{check_ping, BotContext};
+
+const DEBUG_EVENT: &str = "!debug-event";
+/// Commands that command aliases can expand to
+const BUILTIN_COMMANDS: &[&str] = &[DEBUG_EVENT];
+
+/// A custom command name from `bot.command_aliases`, expanding to a built-in command with
+/// optional arguments, for all report rooms or only a single one
+#[derive(Clone, Deserialize)]
+pub struct CommandAlias {
+    room: Option<OwnedRoomId>,
+    alias: String,
+    command: String,
+}
+
+impl CommandAlias {
+    pub fn from_config(config: &Config, report_rooms: &[OwnedRoomId]) -> anyhow::Result<Vec<Self>> {
+        let aliases: Vec<Self> = match config.get("bot.command_aliases") {
+            Ok(aliases) => aliases,
+            Err(ConfigError::NotFound(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        for alias in &aliases {
+            alias.validate(report_rooms)?;
+        }
+        Ok(aliases)
+    }
+
+    fn validate(&self, report_rooms: &[OwnedRoomId]) -> anyhow::Result<()> {
+        if !self.alias.starts_with('!') || self.alias.contains(char::is_whitespace) {
+            anyhow::bail!("Command alias {:?} needs to be a single word starting with !", self.alias);
+        }
+        if BUILTIN_COMMANDS.contains(&self.alias.as_str()) {
+            anyhow::bail!("Command alias {} would shadow the built-in command", self.alias);
+        }
+        let target = self.command.split_whitespace().next().unwrap_or_default();
+        if !BUILTIN_COMMANDS.contains(&target) {
+            anyhow::bail!(
+                "Command {:?} for alias {} is not one of the built-in commands {}",
+                self.command,
+                self.alias,
+                BUILTIN_COMMANDS.join(", "),
+            );
+        }
+        if let Some(room_id) = self.room.as_ref().filter(|room_id| !report_rooms.contains(room_id)) {
+            anyhow::bail!("Room {room_id} of command alias {} is not a report room", self.alias);
+        }
+        Ok(())
+    }
+}
+
+/// Replace a leading command alias that applies in `room_id`, keeping any further arguments.
+/// Room-specific aliases win over ones without a room.
+fn expand_alias<'a>(aliases: &[CommandAlias], room_id: &RoomId, body: &'a str) -> Cow<'a, str> {
+    let body = body.trim_start();
+    let (first, rest) = body.split_once(char::is_whitespace).unwrap_or((body, ""));
+    let matching = aliases
+        .iter()
+        .filter(|alias| alias.alias == first)
+        .filter(|alias| alias.room.as_deref().is_none_or(|room| room == room_id))
+        .max_by_key(|alias| alias.room.is_some());
+    match matching {
+        Some(alias) => Cow::Owned(format!("{} {rest}", alias.command)),
+        None => Cow::Borrowed(body),
+    }
+}
+
+/// Handle commands sent in report rooms
+pub async fn handle_command(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    bot_context: Ctx<BotContext>,
+) {
+    if event.sender == room.own_user_id() || !bot_context.report_rooms.iter().any(|r| r == room.room_id()) {
+        return;
+    }
+    let MessageType::Text(text_content) = &event.content.msgtype else {
+        return;
+    };
+    let body = expand_alias(&bot_context.command_aliases, room.room_id(), &text_content.body);
+    let mut args = body.split_whitespace();
+    let Some(command) = args.next().filter(|c| c.starts_with('!')) else {
+        return;
+    };
+    if !bot_context.admins.is_empty() && !bot_context.admins.contains(&event.sender) {
+        info!("Ignoring command {command} from non-admin {}", event.sender);
+        return;
+    }
+
+    let reply = match command {
+        DEBUG_EVENT => match args.next() {
+            Some(permalink) => debug_event(&room.client(), permalink, &bot_context).await,
+            None => String::from("Usage: `!debug-event <event permalink>`"),
+        },
+        _ => return,
+    };
+    if let Err(e) = room.send(RoomMessageEventContent::notice_markdown(reply)).await {
+        error!("Failed to reply to {command} in {}: {e}", room.room_id());
+    }
+}
+
+/// Explain whether and why the bot would report the event behind a permalink
+async fn debug_event(client: &Client, permalink: &str, bot_context: &BotContext) -> String {
+    let (room_or_alias_id, event_id) = match parse_event_permalink(permalink) {
+        Some(ids) => ids,
+        None => return format!("{permalink} is not an event permalink"),
+    };
+    let room_id: OwnedRoomId = match OwnedRoomId::try_from(room_or_alias_id.clone()) {
+        Ok(room_id) => room_id,
+        Err(alias) => match client.resolve_room_alias(&alias).await {
+            Ok(response) => response.room_id,
+            Err(e) => return format!("Failed to resolve room alias {alias}: {e}"),
+        },
+    };
+    let Some(room) = client.get_room(&room_id) else {
+        return format!("I don't know room {room_id}, so I can't see any of its events");
+    };
+    let timeline_event = match room.event(&event_id, None).await {
+        Ok(timeline_event) => timeline_event,
+        Err(e) => return format!("Failed to fetch {event_id} in {room_id}: {e}"),
+    };
+    let message = match timeline_event.raw().deserialize() {
+        Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(message)
+        ))) => message,
+        Ok(other) => return format!("{event_id} is a {} event, I only report room messages", other.event_type()),
+        Err(e) => return format!("Failed to parse {event_id}, it may be encrypted or redacted: {e}"),
+    };
+
+    let check = check_ping(&message, &room_id, room.own_user_id(), bot_context);
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    let room_kind = if check.is_watched {
+        "watched room"
+    } else if check.is_test {
+        "watched test room"
+    } else {
+        "not watched"
+    };
+    let joined = room.state() == RoomState::Joined;
+    let lines = [
+        format!("Event {event_id} by {} in {room_id}:", message.sender),
+        format!("- Room: {room_kind}"),
+        format!("- Bot joined to the room: {}", yes_no(joined)),
+        format!("- Sent by the bot itself: {}", yes_no(check.own_message)),
+        format!("- Message type {} supported: {}", message.content.msgtype.msgtype(), yes_no(check.supported_type)),
+        format!("- Sent before startup grace period: {}", yes_no(check.too_old)),
+        format!("- Body mentions the bot: {}", yes_no(check.body_match)),
+        format!("- Formatted body mentions the bot: {}", yes_no(check.formatted_match)),
+        format!("- m.mentions includes the bot: {}", yes_no(check.mentions_match)),
+        format!("- Sender opted out of quoting: {}", yes_no(bot_context.is_opted_out(&message.sender))),
+        format!("\nResult: this {} be reported", if joined && check.is_ping() { "would" } else { "would not" }),
+    ];
+    lines.join("\n")
+}
+
+fn parse_event_permalink(permalink: &str) -> Option<(OwnedRoomOrAliasId, OwnedEventId)> {
+    let permalink = permalink.trim_matches(|c| c == '<' || c == '>');
+    let id = MatrixToUri::parse(permalink).map(|uri| uri.id().clone())
+        .or_else(|_| MatrixUri::parse(permalink).map(|uri| uri.id().clone()))
+        .ok()?;
+    match id {
+        MatrixId::Event(room_or_alias_id, event_id) => Some((room_or_alias_id, event_id)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alias(room: Option<&str>, alias: &str, command: &str) -> CommandAlias {
+        CommandAlias {
+            room: room.map(|room| RoomId::parse(room).unwrap()),
+            alias: alias.to_owned(),
+            command: command.to_owned(),
+        }
+    }
+
+    #[test]
+    fn appends_arguments_to_expansion() {
+        let aliases = [alias(None, "!why", "!debug-event")];
+        let room_id = RoomId::parse("!report:example.com").unwrap();
+        let expanded = expand_alias(&aliases, &room_id, "!why https://matrix.to/#/!a:b/$c");
+        assert_eq!(expanded.split_whitespace().collect::<Vec<_>>(), ["!debug-event", "https://matrix.to/#/!a:b/$c"]);
+    }
+
+    #[test]
+    fn room_specific_alias_wins() {
+        let aliases = [
+            alias(Some("!report:example.com"), "!x", "!debug-event room"),
+            alias(None, "!x", "!debug-event global"),
+        ];
+        let report_room = RoomId::parse("!report:example.com").unwrap();
+        let other_room = RoomId::parse("!other:example.com").unwrap();
+        assert_eq!(expand_alias(&aliases, &report_room, "!x").trim_end(), "!debug-event room");
+        assert_eq!(expand_alias(&aliases, &other_room, "!x").trim_end(), "!debug-event global");
+    }
+
+    #[test]
+    fn leaves_other_messages_alone() {
+        let aliases = [alias(None, "!why", "!debug-event")];
+        let room_id = RoomId::parse("!report:example.com").unwrap();
+        assert_eq!(expand_alias(&aliases, &room_id, "!whyever"), "!whyever");
+        assert_eq!(expand_alias(&aliases, &room_id, "hello !why"), "hello !why");
+    }
+
+    #[test]
+    fn rejects_unknown_and_shadowing_aliases() {
+        let report_rooms = [RoomId::parse("!report:example.com").unwrap()];
+        assert!(alias(Some("!report:example.com"), "!why", "!debug-event").validate(&report_rooms).is_ok());
+        assert!(alias(None, "!why", "!debug-evnet").validate(&report_rooms).is_err());
+        assert!(alias(None, "!debug-event", "!debug-event").validate(&report_rooms).is_err());
+        assert!(alias(Some("!other:example.com"), "!why", "!debug-event").validate(&report_rooms).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use log::warn;
+use matrix_sdk::{
+    Room,
+    ruma::{
+        events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent},
+        EventId, UInt, UserId,
+    },
+};
+
+use crate::markdown;
+
+const SNIPPET_LENGTH: usize = 200;
+
+/// Fetch up to `count` messages preceding `event_id` and render them as a markdown list of
+/// permalinks with short snippets, oldest first.
+/// Messages of senders for which `hide_content` returns true are listed without snippet.
+pub async fn preceding_messages(
+    room: &Room,
+    event_id: &EventId,
+    count: usize,
+    hide_content: impl Fn(&UserId) -> bool,
+) -> Option<String> {
+    // The homeserver splits the limit between events before and after the requested one
+    let limit = UInt::try_from(count * 2).unwrap_or(UInt::MAX);
+    let response = match room.event_with_context(event_id, true, limit, None).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Failed to fetch context for {event_id} in {}: {e}", room.room_id());
+            return None;
+        }
+    };
+
+    let mut lines: Vec<String> = response.events_before
+        .into_iter()
+        .filter_map(|event| match event.raw().deserialize() {
+            Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(message)
+            ))) => Some(message),
+            _ => None,
+        })
+        .take(count)
+        .map(|message| {
+            let url = room.room_id().matrix_to_event_uri(message.event_id);
+            if hide_content(&message.sender) {
+                return format!("- [{}]({url})", message.sender);
+            }
+            let body = markdown::snippet(message.content.msgtype.body(), SNIPPET_LENGTH);
+            format!("- [{}]({url}): {}", message.sender, markdown::escape(&body))
+        })
+        .collect();
+
+    if lines.is_empty() {
+        return None;
+    }
+    // events_before is in reverse chronological order
+    lines.reverse();
+    Some(lines.join("\n"))
+}
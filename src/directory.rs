@@ -0,0 +1,119 @@
+use config::Config;
+use log::{error, info, warn};
+use matrix_sdk::{
+    Client,
+    ruma::{
+        api::client::directory::get_public_rooms_filtered,
+        directory::{Filter, PublicRoomsChunk},
+        OwnedRoomId, OwnedServerName, UInt,
+    },
+};
+use std::{collections::{HashMap, HashSet}, time::Duration};
+
+use crate::{markdown, notify_report_rooms, BotContext};
+
+const PAGE_SIZE: u32 = 100;
+const MAX_PAGES: usize = 5;
+const TOPIC_SNIPPET_LENGTH: usize = 200;
+
+/// Watches the public room directory for rooms matching configured keywords
+pub struct DirectoryWatch {
+    keywords: Vec<String>,
+    server: Option<OwnedServerName>,
+    interval: Duration,
+}
+
+impl DirectoryWatch {
+    /// Read the optional `directory_watch` config section, returns None if no keywords are configured
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let keywords = config.get::<Vec<String>>("directory_watch.keywords").unwrap_or_default();
+        if keywords.is_empty() {
+            return Ok(None);
+        }
+        let server = match config.get::<String>("directory_watch.server") {
+            Ok(server) => Some(server.try_into()?),
+            Err(_) => None,
+        };
+        Ok(Some(DirectoryWatch {
+            keywords,
+            server,
+            interval: Duration::from_secs(config.get::<u64>("directory_watch.interval_secs").unwrap_or(3600)),
+        }))
+    }
+
+    /// Periodically search the directory and report rooms that weren't there before. Rooms that
+    /// are already listed on the first successful search for a keyword are considered known and
+    /// not reported.
+    pub async fn run(self, client: Client, bot_context: BotContext) {
+        // Rooms seen per keyword, a keyword is only in here once it was searched successfully
+        let mut known: HashMap<&str, HashSet<OwnedRoomId>> = HashMap::new();
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            for keyword in self.keywords.iter() {
+                let (rooms, complete) = match self.search(&client, keyword).await {
+                    Ok(result) => result,
+                    Err(e) => {
+                        error!("Failed to search room directory for {keyword}: {e}");
+                        continue;
+                    }
+                };
+                let seeded = known.contains_key(keyword.as_str());
+                // Rooms moving across the page limit would look new, so only report from
+                // complete results
+                if seeded && !complete {
+                    warn!("More than {} public rooms match {keyword}, not reporting any of them as new", PAGE_SIZE as usize * MAX_PAGES);
+                }
+                for room in rooms {
+                    let already_known = known.values().any(|rooms| rooms.contains(&room.room_id));
+                    known.entry(keyword).or_default().insert(room.room_id.clone());
+                    if already_known || !seeded || !complete {
+                        continue;
+                    }
+                    info!("New public room {} matches {keyword}", room.room_id);
+                    let msg = format!("New public room matching \"{}\": {}", markdown::escape(keyword), describe(&room));
+                    notify_report_rooms(&client, &bot_context, &msg, false).await;
+                }
+                if !seeded {
+                    let listed = known.entry(keyword).or_default().len();
+                    info!("Room directory watch found {listed} rooms matching {keyword} already listed");
+                }
+            }
+        }
+    }
+
+    /// Search the directory for `keyword`, also returns whether all results fit into `MAX_PAGES`
+    async fn search(&self, client: &Client, keyword: &str) -> anyhow::Result<(Vec<PublicRoomsChunk>, bool)> {
+        let mut rooms = Vec::new();
+        let mut since = None;
+        for _ in 0..MAX_PAGES {
+            let mut filter = Filter::new();
+            filter.generic_search_term = Some(keyword.to_owned());
+            let mut request = get_public_rooms_filtered::v3::Request::new();
+            request.server = self.server.clone();
+            request.limit = Some(UInt::from(PAGE_SIZE));
+            request.since = since;
+            request.filter = filter;
+            let response = client.public_rooms_filtered(request).await?;
+            rooms.extend(response.chunk);
+            since = response.next_batch;
+            if since.is_none() {
+                return Ok((rooms, true));
+            }
+        }
+        Ok((rooms, false))
+    }
+}
+
+fn describe(room: &PublicRoomsChunk) -> String {
+    let name = room.name.as_deref().unwrap_or("unnamed room");
+    let link = match &room.canonical_alias {
+        Some(alias) => format!("{} ({alias})", markdown::escape(name)),
+        None => format!("{} ({})", markdown::escape(name), room.room_id),
+    };
+    let mut description = format!("{link}, {} members", room.num_joined_members);
+    if let Some(topic) = &room.topic {
+        description.push_str(&format!(", topic: {}", markdown::escape(&markdown::snippet(topic, TOPIC_SNIPPET_LENGTH))));
+    }
+    description
+}
@@ -0,0 +1,107 @@
+use log::{error, info, warn};
+use matrix_sdk::{
+    Room,
+    ruma::{events::room::message::RoomMessageEventContent, OwnedEventId},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+use crate::telemetry;
+
+#[derive(Default)]
+struct Echoes {
+    /// Sent events we are still waiting to see in sync
+    pending: HashMap<OwnedEventId, oneshot::Sender<()>>,
+    /// Echoes that arrived before anyone started waiting for them
+    early: HashMap<OwnedEventId, Instant>,
+}
+
+/// Confirms that sent reports show up in the bot's own sync, to catch sends that succeeded but
+/// never made it into the room
+pub struct EchoWatcher {
+    timeout: Duration,
+    resend_attempts: usize,
+    echoes: Mutex<Echoes>,
+}
+
+impl EchoWatcher {
+    pub fn new(timeout: Duration, resend_attempts: usize) -> Self {
+        EchoWatcher {
+            timeout,
+            resend_attempts,
+            echoes: Mutex::new(Echoes::default()),
+        }
+    }
+
+    /// Called for every own event coming down sync
+    pub fn received(&self, event_id: OwnedEventId) {
+        let mut echoes = self.echoes.lock().unwrap();
+        if let Some(waiter) = echoes.pending.remove(&event_id) {
+            let _ = waiter.send(());
+        } else {
+            let timeout = self.timeout;
+            echoes.early.retain(|_, received| received.elapsed() < timeout);
+            echoes.early.insert(event_id, Instant::now());
+        }
+    }
+
+    async fn wait_for(&self, event_id: OwnedEventId) -> bool {
+        let receiver = {
+            let mut echoes = self.echoes.lock().unwrap();
+            if echoes.early.remove(&event_id).is_some() {
+                return true;
+            }
+            let (sender, receiver) = oneshot::channel();
+            echoes.pending.insert(event_id.clone(), sender);
+            receiver
+        };
+        let confirmed = tokio::time::timeout(self.timeout, receiver).await.is_ok_and(|r| r.is_ok());
+        if !confirmed {
+            self.echoes.lock().unwrap().pending.remove(&event_id);
+        }
+        confirmed
+    }
+
+    /// Watch in the background for the sent report to come down sync, and re-send it if it doesn't
+    pub fn confirm(self: &Arc<Self>, report_room: Room, event_id: OwnedEventId, content: RoomMessageEventContent) {
+        let watcher = self.clone();
+        tokio::spawn(async move {
+            let mut event_id = event_id;
+            for attempt in 0..=watcher.resend_attempts {
+                if watcher.wait_for(event_id.clone()).await {
+                    return;
+                }
+                // The echo may only be stuck behind a busy sync loop, so ask the server before
+                // re-sending and pinging the room twice
+                if report_room.event(&event_id, None).await.is_ok() {
+                    info!("Report {event_id} in {} did not show up in sync in time, but the server has it", report_room.room_id());
+                    return;
+                }
+                if attempt == watcher.resend_attempts {
+                    break;
+                }
+                warn!("Report {event_id} in {} did not show up in sync within {:?}, re-sending", report_room.room_id(), watcher.timeout);
+                match report_room.send(content.clone()).await {
+                    Ok(response) => {
+                        info!("Re-sent report {event_id} in {} as {}", report_room.room_id(), response.event_id);
+                        event_id = response.event_id;
+                    }
+                    Err(e) => {
+                        error!("Failed to re-send report {event_id} in {}: {e}", report_room.room_id());
+                        break;
+                    }
+                }
+            }
+            error!("Report {event_id} in {} never showed up in sync, the report may be lost", report_room.room_id());
+            telemetry::capture_error(
+                "Report never showed up in sync, the report may be lost",
+                Some(report_room.room_id()),
+                Some(&event_id),
+            );
+        });
+    }
+}
@@ -0,0 +1,190 @@
+use config::Config;
+use log::{info, warn};
+use matrix_sdk::{
+    Client, Room,
+    event_handler::Ctx,
+    ruma::{
+        events::room::member::{MembershipState, OriginalSyncRoomMemberEvent},
+        OwnedMxcUri, OwnedUserId, UserId,
+    },
+};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::{markdown, notify_report_rooms, BotContext};
+
+/// How long fetched profiles of protected users are trusted before fetching them again
+const PROFILE_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+struct ProtectedProfile {
+    user_id: OwnedUserId,
+    names: Vec<String>,
+    avatar_url: Option<OwnedMxcUri>,
+}
+
+/// Detects watched room members taking on the name or avatar of a protected user
+pub struct ImpersonationWatch {
+    protected_users: Vec<OwnedUserId>,
+    profiles: Mutex<Option<(Instant, Vec<ProtectedProfile>)>>,
+}
+
+impl ImpersonationWatch {
+    /// Read the optional `impersonation` config section, returns None if it's not enabled.
+    /// The bot itself is always protected.
+    pub fn from_config(config: &Config, bot_mxid: &UserId) -> anyhow::Result<Option<Self>> {
+        if !config.get::<bool>("impersonation.enabled").unwrap_or(false) {
+            return Ok(None);
+        }
+        let mut protected_users = config.get::<Vec<String>>("impersonation.protected_users")
+            .unwrap_or_default()
+            .into_iter()
+            .map(UserId::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if !protected_users.iter().any(|u| u == bot_mxid) {
+            protected_users.push(bot_mxid.to_owned());
+        }
+        Ok(Some(ImpersonationWatch {
+            protected_users,
+            profiles: Mutex::new(None),
+        }))
+    }
+
+    /// Find the protected user a member's display name or avatar resembles, if any
+    async fn impersonated(&self, client: &Client, user_id: &UserId, displayname: Option<&str>, avatar_url: Option<&OwnedMxcUri>) -> Option<(OwnedUserId, &'static str)> {
+        let normalized_name = displayname.map(normalize);
+        let mut profiles = self.profiles.lock().await;
+        if profiles.as_ref().is_none_or(|(fetched, _)| fetched.elapsed() >= PROFILE_REFRESH_INTERVAL) {
+            *profiles = Some((Instant::now(), self.fetch_profiles(client).await));
+        }
+        let (_, profiles) = profiles.as_ref()?;
+        for profile in profiles.iter().filter(|p| p.user_id != user_id) {
+            if let Some(name) = &normalized_name {
+                if profile.names.iter().any(|protected| is_similar(name, protected)) {
+                    return Some((profile.user_id.clone(), "display name"));
+                }
+            }
+            if avatar_url.is_some() && avatar_url == profile.avatar_url.as_ref() {
+                return Some((profile.user_id.clone(), "avatar"));
+            }
+        }
+        None
+    }
+
+    async fn fetch_profiles(&self, client: &Client) -> Vec<ProtectedProfile> {
+        let mut profiles = Vec::new();
+        for user_id in self.protected_users.iter() {
+            let mut names = vec![normalize(user_id.localpart())];
+            let mut avatar_url = None;
+            match client.account().fetch_user_profile_of(user_id).await {
+                Ok(profile) => {
+                    names.extend(profile.displayname.as_deref().map(normalize));
+                    avatar_url = profile.avatar_url;
+                }
+                Err(e) => warn!("Failed to fetch profile of protected user {user_id}: {e}"),
+            }
+            names.retain(|n| !n.is_empty());
+            profiles.push(ProtectedProfile {
+                user_id: user_id.clone(),
+                names,
+                avatar_url,
+            });
+        }
+        profiles
+    }
+}
+
+pub async fn handle_member_event(
+    event: OriginalSyncRoomMemberEvent,
+    room: Room,
+    bot_context: Ctx<BotContext>,
+) {
+    let Some(watch) = &bot_context.impersonation_watch else {
+        return;
+    };
+    if !bot_context.watched_rooms.iter().chain(bot_context.watched_test_rooms.iter()).any(|r| r == room.room_id()) {
+        return;
+    }
+    if event.content.membership != MembershipState::Join {
+        return;
+    }
+    let Ok(user_id) = UserId::parse(&event.state_key) else {
+        return;
+    };
+    // Only look at what changed, so we don't alert again on unrelated membership updates
+    let prev = event.unsigned.prev_content.as_ref().filter(|p| p.membership == MembershipState::Join);
+    let displayname = event.content.displayname.as_deref()
+        .filter(|name| prev.is_none_or(|p| p.displayname.as_deref() != Some(*name)));
+    let avatar_url = event.content.avatar_url.as_ref()
+        .filter(|avatar| prev.is_none_or(|p| p.avatar_url.as_ref() != Some(*avatar)));
+    if displayname.is_none() && avatar_url.is_none() {
+        return;
+    }
+
+    let client = room.client();
+    if let Some((protected, what)) = watch.impersonated(&client, &user_id, displayname, avatar_url).await {
+        info!("{user_id} in {} changed their {what} to resemble {protected}", room.room_id());
+        let url = room.room_id().matrix_to_event_uri(event.event_id.clone());
+        let msg = format!(
+            "Possible impersonation: {user_id} (now named \"{}\") changed their {what} to resemble {protected} at {url}",
+            markdown::escape(event.content.displayname.as_deref().unwrap_or_default()),
+        );
+        let is_test = !bot_context.watched_rooms.iter().any(|r| r == room.room_id());
+        notify_report_rooms(&client, &bot_context, &msg, !is_test).await;
+    }
+}
+
+/// Reduce a name to lowercase ASCII letters and digits, mapping look-alike characters
+fn normalize(name: &str) -> String {
+    let cured = decancer::cure(name, decancer::Options::default())
+        .map(|cured| cured.to_string())
+        .unwrap_or_else(|_| name.to_lowercase());
+    cured.chars().filter(char::is_ascii_alphanumeric).collect()
+}
+
+/// Names count as similar when they're equal after normalization, or just one edit apart for
+/// names long enough for that not to be a coincidence
+fn is_similar(a: &str, b: &str) -> bool {
+    a == b || (a.len().min(b.len()) >= 5 && edit_distance(a, b) <= 1)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev_diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev_diagonal + usize::from(ca != *cb);
+            prev_diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance_counts_chars() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("same", "same"), 0);
+        // Multi-byte characters count as one edit
+        assert_eq!(edit_distance("modäd", "modad"), 1);
+    }
+
+    #[test]
+    fn long_names_one_edit_apart_are_similar() {
+        assert!(is_similar("moderator", "moderat0r"));
+        assert!(is_similar("moderator", "moderators"));
+        assert!(!is_similar("moderator", "mod3rat0r"));
+    }
+
+    #[test]
+    fn short_names_need_to_match_exactly() {
+        assert!(is_similar("bob", "bob"));
+        assert!(!is_similar("bob", "bot"));
+    }
+}
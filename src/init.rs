@@ -0,0 +1,153 @@
+use anyhow::Context;
+use matrix_sdk::{
+    config::SyncSettings,
+    Client, Room,
+    ruma::{OwnedRoomId, RoomId},
+};
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+const CONFIG_PATH: &str = "config.yaml";
+
+/// Interactively ask for login and rooms, verify them and write a config.yaml
+pub async fn run() -> anyhow::Result<()> {
+    println!("This will set up a new {CONFIG_PATH} for matrix-report-mention-bot.");
+    if Path::new(CONFIG_PATH).exists()
+        && !confirm(&format!("{CONFIG_PATH} already exists, overwrite it?"))?
+    {
+        return Ok(());
+    }
+
+    let (client, homeserver_url, mxid, password) = loop {
+        let server = prompt("Homeserver (server name or URL)")?;
+        let mxid = prompt("Bot user ID (e.g. @bot:example.com)")?;
+        let password = prompt("Bot password (will be echoed)")?;
+        let client = match Client::builder().server_name_or_homeserver_url(&server).build().await {
+            Ok(client) => client,
+            Err(e) => {
+                println!("Failed to find a homeserver at {server}: {e}");
+                continue;
+            }
+        };
+        match client.matrix_auth().login_username(&mxid, &password)
+            .initial_device_display_name("matrix-report-mention-bot setup")
+            .await
+        {
+            Ok(_) => break (client.clone(), client.homeserver().to_string(), mxid, password),
+            Err(e) => println!("Login failed: {e}"),
+        }
+    };
+    let device_name = prompt_with_default("Device name", "matrix-report-mention-bot")?;
+
+    println!("Logged in, fetching joined rooms...");
+    client.sync_once(SyncSettings::default()).await?;
+    let mut rooms = client.joined_rooms();
+    rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+    for (i, room) in rooms.iter().enumerate() {
+        let name = room.display_name().await.map(|n| n.to_string()).unwrap_or_default();
+        println!("  [{}] {name} ({})", i + 1, room.room_id());
+    }
+    println!("Select rooms by their number or room ID, separated by commas.");
+
+    let report_rooms = loop {
+        let selected = select_rooms("Report rooms, where pings get reported to", &rooms)?;
+        if selected.is_empty() {
+            println!("At least one report room is required");
+            continue;
+        }
+        break selected;
+    };
+    let watched_rooms = select_rooms("Watched rooms, where the bot listens for pings", &rooms)?;
+    let watched_test_rooms = select_rooms("Watched test rooms, reported without room ping (optional)", &rooms)?;
+
+    // The bot does a login of its own on first start, so don't leave this session behind
+    if let Err(e) = client.matrix_auth().logout().await {
+        println!("Failed to log out the setup session, you may want to remove it manually: {e}");
+    }
+
+    let config = format!(
+        "login:\n  homeserver_url: {}\n  mxid: {}\n  password: {}\n  device_name: {}\nbot:\n  report_rooms:{}\n  watched_rooms:{}\n  watched_test_rooms:{}\n",
+        yaml_string(&homeserver_url),
+        yaml_string(&mxid),
+        yaml_string(&password),
+        yaml_string(&device_name),
+        yaml_list(&report_rooms),
+        yaml_list(&watched_rooms),
+        yaml_list(&watched_test_rooms),
+    );
+    std::fs::write(CONFIG_PATH, config).with_context(|| format!("Failed to write {CONFIG_PATH}"))?;
+    println!("Wrote {CONFIG_PATH}, see example-config.yaml for further options.");
+    Ok(())
+}
+
+fn prompt(question: &str) -> anyhow::Result<String> {
+    loop {
+        let answer = read_line(&format!("{question}: "))?;
+        if !answer.is_empty() {
+            return Ok(answer);
+        }
+    }
+}
+
+fn prompt_with_default(question: &str, default: &str) -> anyhow::Result<String> {
+    let answer = read_line(&format!("{question} [{default}]: "))?;
+    Ok(if answer.is_empty() { default.to_owned() } else { answer })
+}
+
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    let answer = read_line(&format!("{question} [y/N]: "))?;
+    Ok(matches!(answer.to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn read_line(prompt: &str) -> anyhow::Result<String> {
+    print!("{prompt}");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        anyhow::bail!("Setup aborted");
+    }
+    Ok(line.trim().to_owned())
+}
+
+fn select_rooms(question: &str, rooms: &[Room]) -> anyhow::Result<Vec<OwnedRoomId>> {
+    'ask: loop {
+        let answer = read_line(&format!("{question}: "))?;
+        let mut selected = Vec::new();
+        for item in answer.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let room_id = match item.parse::<usize>() {
+                Ok(i) if (1..=rooms.len()).contains(&i) => rooms[i - 1].room_id().to_owned(),
+                Ok(i) => {
+                    println!("There is no room number {i}");
+                    continue 'ask;
+                }
+                Err(_) => match RoomId::parse(item) {
+                    Ok(room_id) => room_id,
+                    Err(e) => {
+                        println!("{item} is neither a room number nor a valid room ID: {e}");
+                        continue 'ask;
+                    }
+                },
+            };
+            if !rooms.iter().any(|r| r.room_id() == room_id) {
+                println!("Note: the bot is not joined to {room_id} yet");
+            }
+            selected.push(room_id);
+        }
+        return Ok(selected);
+    }
+}
+
+fn yaml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn yaml_list(room_ids: &[OwnedRoomId]) -> String {
+    if room_ids.is_empty() {
+        return String::from(" []");
+    }
+    room_ids.iter()
+        .map(|room_id| format!("\n    - {}", yaml_string(room_id.as_str())))
+        .collect()
+}
@@ -0,0 +1,99 @@
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+
+/// Matrix limits whole events to 65536 bytes, keep some headroom for the fields the homeserver
+/// adds around the content
+const MAX_CONTENT_BYTES: usize = 60_000;
+/// Megolm encryption and base64 grow the content by about a third
+const MAX_ENCRYPTED_CONTENT_BYTES: usize = 45_000;
+
+/// Whether a message content is small enough to be sent as a single event, in a room that is
+/// `encrypted` or not
+pub fn fits(content: &RoomMessageEventContent, encrypted: bool) -> bool {
+    let max = if encrypted { MAX_ENCRYPTED_CONTENT_BYTES } else { MAX_CONTENT_BYTES };
+    serde_json::to_vec(content).is_ok_and(|json| json.len() <= max)
+}
+
+/// Split a markdown message into as few chunks as needed for `fits` to accept each of them.
+/// Splits happen at line breaks, only lines that are too long on their own get split inside.
+pub fn split_to_fit(msg: &str, fits: impl Fn(&str) -> bool) -> Vec<String> {
+    if fits(msg) {
+        return vec![msg.to_owned()];
+    }
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for line in msg.split('\n') {
+        let candidate = if current.is_empty() { line.to_owned() } else { format!("{current}\n{line}") };
+        if fits(&candidate) {
+            current = candidate;
+            continue;
+        }
+        push_chunk(&mut chunks, std::mem::take(&mut current));
+        let mut rest = line;
+        while !fits(rest) {
+            let end = longest_fitting_prefix(rest, &fits);
+            push_chunk(&mut chunks, rest[..end].to_owned());
+            rest = &rest[end..];
+        }
+        current = rest.to_owned();
+    }
+    push_chunk(&mut chunks, current);
+    chunks
+}
+
+fn push_chunk(chunks: &mut Vec<String>, chunk: String) {
+    let chunk = chunk.trim_matches('\n');
+    if !chunk.is_empty() {
+        chunks.push(chunk.to_owned());
+    }
+}
+
+/// Byte length of the longest prefix of `s` that fits, always at least one character
+fn longest_fitting_prefix(s: &str, fits: impl Fn(&str) -> bool) -> usize {
+    let boundaries: Vec<usize> = s.char_indices().map(|(i, _)| i).skip(1).chain([s.len()]).collect();
+    let (mut low, mut high) = (0, boundaries.len() - 1);
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if fits(&s[..boundaries[mid]]) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    boundaries[low]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_bytes(max: usize) -> impl Fn(&str) -> bool {
+        move |chunk| chunk.len() <= max
+    }
+
+    #[test]
+    fn keeps_message_that_fits() {
+        assert_eq!(split_to_fit("short\nmessage", max_bytes(100)), ["short\nmessage"]);
+    }
+
+    #[test]
+    fn splits_at_line_breaks() {
+        assert_eq!(split_to_fit("aaaa\nbbbb\ncccc", max_bytes(9)), ["aaaa\nbbbb", "cccc"]);
+    }
+
+    #[test]
+    fn splits_long_line_inside() {
+        assert_eq!(split_to_fit("ab\ncdefghij\nkl", max_bytes(4)), ["ab", "cdef", "ghij", "kl"]);
+    }
+
+    #[test]
+    fn splits_at_char_boundaries() {
+        // Two bytes per character, so a limit of five bytes fits two of them
+        assert_eq!(split_to_fit("äöüß", max_bytes(5)), ["äö", "üß"]);
+        assert_eq!(longest_fitting_prefix("äöü", max_bytes(3)), 2);
+    }
+
+    #[test]
+    fn prefix_has_at_least_one_char() {
+        assert_eq!(longest_fitting_prefix("äb", |_| false), 2);
+    }
+}
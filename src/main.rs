@@ -1,49 +1,163 @@
+use aho_corasick::AhoCorasick;
+use anyhow::Context;
 use config::{Config, Value};
-use log::{debug, info, error};
+use log::{debug, info, error, warn};
+use serde::Deserialize;
 use url::Url;
 use matrix_sdk::{
     config::SyncSettings,
+    sync::SyncResponse,
     event_handler::Ctx,
     authentication::matrix::MatrixSession,
     Client, Room, RoomState,
     ruma::events::{
+        key::verification::{
+            done::ToDeviceKeyVerificationDoneEvent, key::ToDeviceKeyVerificationKeyEvent,
+            request::ToDeviceKeyVerificationRequestEvent, start::ToDeviceKeyVerificationStartEvent,
+        },
+        room::member::{OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent},
         room::message::{
             MessageType, OriginalSyncRoomMessageEvent,
-            RoomMessageEventContent,
+            FormattedBody, RoomMessageEventContent,
         },
-        reaction::ReactionEventContent,
-        relation::Annotation,
-        Mentions,
+        MessageLikeEventType, Mentions,
     },
-    ruma::{RoomId, OwnedRoomId},
+    ruma::{EventId, MatrixToUri, MilliSecondsSinceUnixEpoch, RoomId, OwnedRoomId, OwnedServerName, OwnedUserId, ServerName, UserId},
+};
+use std::sync::{atomic::AtomicU64, Arc};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{
+    fs,
+    runtime::{self, Runtime},
+    sync::Semaphore,
 };
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::fs;
+
+mod ack;
+mod canary;
+mod commands;
+mod context;
+mod directory;
+mod echo;
+mod impersonation;
+mod init;
+mod limits;
+mod markdown;
+mod optout;
+mod panics;
+mod repeats;
+mod summarize;
+mod telemetry;
+mod translate;
+mod urls;
+mod verification;
+mod watchdog;
+
+use ack::{Acknowledger, AckFallback};
+use canary::Canary;
+use commands::CommandAlias;
+use directory::DirectoryWatch;
+use echo::EchoWatcher;
+use impersonation::ImpersonationWatch;
+use optout::OptOuts;
+use repeats::{RepeatTracker, Reservation, SentReport};
+use summarize::Summarizer;
+use translate::Translator;
+use urls::UrlInspector;
 
 #[derive(Clone)]
 struct BotContext {
+    // Server time at startup, messages older than this minus the grace period are not reported
     launched_ts: u128,
-    bot_mxid: String,
-    bot_mxid_http_escaped: String,
+    grace_period_ms: u128,
+    mention_matcher: AhoCorasick,
     watched_rooms: Vec<OwnedRoomId>,
     watched_test_rooms: Vec<OwnedRoomId>,
     report_rooms: Vec<OwnedRoomId>,
+    report_room_via_servers: Vec<OwnedServerName>,
+    delivery_permits: Arc<Semaphore>,
+    context_messages: usize,
+    http_client: reqwest::Client,
+    // Limits concurrent summarizer and translation requests across all deliveries
+    helper_permits: Arc<Semaphore>,
+    translator: Option<Arc<Translator>>,
+    summarizer: Option<Arc<Summarizer>>,
+    repeat_tracker: Option<Arc<RepeatTracker>>,
+    acknowledger: Arc<Acknowledger>,
+    echo_watcher: Option<Arc<EchoWatcher>>,
+    impersonation_watch: Option<Arc<ImpersonationWatch>>,
+    url_inspector: Option<Arc<UrlInspector>>,
+    mirror_attachments: bool,
+    admins: Vec<OwnedUserId>,
+    canary: Option<Arc<Canary>>,
+    federation_lag_threshold: Option<Duration>,
+    optouts: Option<Arc<OptOuts>>,
+    handler_panics: Arc<AtomicU64>,
+    verification_allowlist: Vec<OwnedUserId>,
+    command_aliases: Vec<CommandAlias>,
+}
+
+impl BotContext {
+    fn is_opted_out(&self, user_id: &UserId) -> bool {
+        self.optouts.as_ref().is_some_and(|optouts| optouts.contains(user_id))
+    }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("init") => return build_runtime(None)?.block_on(init::run()),
+        Some(other) => anyhow::bail!("Unknown subcommand {other}, expected no subcommand or init"),
+    }
+
     let config = Config::builder()
         .add_source(config::File::with_name("config.yaml"))
         .build()
         .unwrap();
 
+    // Needs to be initialized before the async runtime starts
+    let _sentry = telemetry::init(&config);
+
+    build_runtime(Some(&config))?.block_on(run(config))
+}
+
+/// Build the async runtime as configured in the optional `runtime` config section
+fn build_runtime(config: Option<&Config>) -> anyhow::Result<Runtime> {
+    let get = |key: &str| config.and_then(|c| c.get::<usize>(key).ok());
+    let single_threaded = config.and_then(|c| c.get::<bool>("runtime.single_threaded").ok()).unwrap_or(false);
+    let mut builder = if single_threaded {
+        runtime::Builder::new_current_thread()
+    } else {
+        runtime::Builder::new_multi_thread()
+    };
+    match get("runtime.worker_threads") {
+        Some(0) => anyhow::bail!("runtime.worker_threads needs to be at least 1"),
+        Some(_) if single_threaded => warn!("Ignoring runtime.worker_threads, as runtime.single_threaded is set"),
+        Some(worker_threads) => {
+            builder.worker_threads(worker_threads);
+        }
+        None => {}
+    }
+    match get("runtime.max_blocking_threads") {
+        Some(0) => anyhow::bail!("runtime.max_blocking_threads needs to be at least 1"),
+        Some(max_blocking_threads) => {
+            builder.max_blocking_threads(max_blocking_threads);
+        }
+        None => {}
+    }
+    Ok(builder.enable_all().build()?)
+}
+
+async fn run(config: Config) -> anyhow::Result<()> {
     let hs = config.get::<String>("login.homeserver_url").expect("Homeserver url missing in config");
     let hs_url = Url::parse(&hs).expect("Invalid homeserver url");
     let mxid = config.get::<String>("login.mxid").expect("Bot mxid missing in config");
     let password = config.get::<String>("login.password").expect("Password missing in config");
 
-    let report_rooms = config.get_array("bot.report_rooms")
+    let report_rooms: Vec<OwnedRoomId> = config.get_array("bot.report_rooms")
         .expect("Missing bot.report_rooms in config")
         .into_iter()
         .map(Value::into_string)
@@ -61,7 +175,7 @@ async fn main() -> anyhow::Result<()> {
         .map(|room_id| room_id.expect("Invalid roomId in bot.watched_rooms"))
         .collect();
 
-    let watched_test_rooms = config.get_array("bot.watched_test_rooms")
+    let watched_test_rooms: Vec<OwnedRoomId> = config.get_array("bot.watched_test_rooms")
         .unwrap_or_default()
         .into_iter()
         .map(Value::into_string)
@@ -70,23 +184,112 @@ async fn main() -> anyhow::Result<()> {
         .map(|room_id| room_id.expect("Invalid roomId in bot.watched_test_rooms"))
         .collect();
 
+    let report_room_via_servers = config.get_array("bot.report_room_via_servers")
+        .unwrap_or_default()
+        .into_iter()
+        .map(Value::into_string)
+        .map(Result::unwrap_or_default)
+        .map(ServerName::parse)
+        .map(|server| server.expect("Invalid server name in bot.report_room_via_servers"))
+        .collect();
+
+    let admins = config.get_array("bot.admins")
+        .unwrap_or_default()
+        .into_iter()
+        .map(Value::into_string)
+        .map(Result::unwrap_or_default)
+        .map(UserId::parse)
+        .map(|user_id| user_id.expect("Invalid user ID in bot.admins"))
+        .collect();
+
+    let verification_allowlist = config.get_array("bot.verification_allowlist")
+        .unwrap_or_default()
+        .into_iter()
+        .map(Value::into_string)
+        .map(Result::unwrap_or_default)
+        .map(UserId::parse)
+        .map(|user_id| user_id.expect("Invalid user ID in bot.verification_allowlist"))
+        .collect();
+
+    let max_concurrent_reports = config.get::<usize>("bot.max_concurrent_reports").unwrap_or(4);
+    if max_concurrent_reports == 0 {
+        anyhow::bail!("bot.max_concurrent_reports needs to be at least 1");
+    }
+    let max_concurrent_helpers = config.get::<usize>("runtime.max_concurrent_helpers").unwrap_or(2);
+    if max_concurrent_helpers == 0 {
+        anyhow::bail!("runtime.max_concurrent_helpers needs to be at least 1");
+    }
+
+    let ack_fallback = match config.get::<String>("bot.ack_fallback").unwrap_or(String::from("nothing")).as_str() {
+        "nothing" => AckFallback::Nothing,
+        "threaded_notice" => AckFallback::ThreadedNotice {
+            text: config.get::<String>("bot.ack_notice_text")
+                .unwrap_or(String::from("I have reported this message to the moderators.")),
+            min_interval: Duration::from_secs(config.get::<u64>("bot.ack_notice_min_interval_secs").unwrap_or(3600)),
+        },
+        other => anyhow::bail!("Unknown bot.ack_fallback {other}, expected nothing or threaded_notice"),
+    };
+
+    let command_aliases = CommandAlias::from_config(&config, &report_rooms)?;
+    let directory_watch = DirectoryWatch::from_config(&config)?;
+    let canary = Canary::from_config(&config, &watched_test_rooms)?.map(Arc::new);
+    let impersonation_watch = ImpersonationWatch::from_config(&config, &UserId::parse(&mxid)?)?;
+
     let data_dir = dirs::data_dir().expect("no data_dir directory found").join("matrix-report-mention-bot");
     let db_path = data_dir.join("db");
     let session_path = data_dir.join("session");
+    let optouts = if config.get::<bool>("bot.allow_optout").unwrap_or(false) {
+        Some(Arc::new(OptOuts::load(data_dir.join("optouts.json")).await?))
+    } else {
+        None
+    };
 
     // For mention detection in formatted content
     let bot_mxid_http_escaped = mxid.replace("@", "%40").replace(":", "%3A");
+    let mention_matcher = build_mention_matcher(&[&mxid, &bot_mxid_http_escaped])?;
 
-    let bot_context = BotContext {
+    let mut bot_context = BotContext {
         launched_ts: SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
             .as_millis(),
-        bot_mxid: mxid.clone(),
-        bot_mxid_http_escaped: bot_mxid_http_escaped.clone(),
+        grace_period_ms: u128::from(config.get::<u64>("bot.grace_period_secs").unwrap_or(10)) * 1000,
+        mention_matcher,
         watched_rooms,
         watched_test_rooms,
         report_rooms,
+        report_room_via_servers,
+        delivery_permits: Arc::new(Semaphore::new(max_concurrent_reports)),
+        context_messages: config.get::<usize>("bot.context_messages").unwrap_or(0),
+        http_client: build_http_client(&config)?,
+        helper_permits: Arc::new(Semaphore::new(max_concurrent_helpers)),
+        translator: Translator::from_config(&config)?.map(Arc::new),
+        summarizer: Summarizer::from_config(&config)?.map(Arc::new),
+        repeat_tracker: match config.get::<u64>("bot.repeat_ping_window_secs").unwrap_or(0) {
+            0 => None,
+            secs => Some(Arc::new(RepeatTracker::new(Duration::from_secs(secs)))),
+        },
+        acknowledger: Arc::new(Acknowledger::new(ack_fallback)),
+        echo_watcher: match config.get::<u64>("bot.echo_timeout_secs").unwrap_or(60) {
+            0 => None,
+            secs => Some(Arc::new(EchoWatcher::new(
+                Duration::from_secs(secs),
+                config.get::<usize>("bot.echo_resend_attempts").unwrap_or(1),
+            ))),
+        },
+        impersonation_watch: impersonation_watch.map(Arc::new),
+        url_inspector: UrlInspector::from_config(&config).map(Arc::new),
+        mirror_attachments: config.get::<bool>("bot.mirror_attachments").unwrap_or(false),
+        admins,
+        canary: canary.clone(),
+        federation_lag_threshold: match config.get::<u64>("bot.federation_lag_threshold_secs").unwrap_or(300) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        optouts,
+        handler_panics: Arc::new(AtomicU64::new(0)),
+        verification_allowlist,
+        command_aliases,
     };
 
     debug!("Data dir configured at {}", data_dir.to_str().unwrap_or_default());
@@ -94,6 +297,7 @@ async fn main() -> anyhow::Result<()> {
 
     let client = Client::builder()
         .homeserver_url(&hs_url)
+        .http_client(bot_context.http_client.clone())
         .sqlite_store(&db_path, None)
         .build()
         .await?;
@@ -121,19 +325,113 @@ async fn main() -> anyhow::Result<()> {
         fs::write(session_path, serialized_session).await?;
     }
 
-    client.add_event_handler_context(bot_context);
-
     // Sync once without message handler to not deal with old messages
     let sync_response = client.sync_once(SyncSettings::default()).await.unwrap();
     info!("Initial sync finished with token {}, start listening for events", sync_response.next_batch);
 
+    // Judge message age by our homeserver's clock rather than ours, to not depend on the local
+    // clock being in sync. Falls back to local time if no event in the sync came from our
+    // homeserver.
+    let own_user_id = UserId::parse(&mxid)?;
+    if let Some(server_ts) = server_time(&sync_response, own_user_id.server_name()) {
+        debug!("Homeserver time at initial sync is {server_ts}, local time is {}", bot_context.launched_ts);
+        bot_context.launched_ts = server_ts;
+    }
+    client.add_event_handler_context(bot_context.clone());
+
+    match config.get::<u64>("bot.watched_room_missing_alert_secs").unwrap_or(600) {
+        0 => {}
+        secs => {
+            tokio::spawn(watchdog::run(client.clone(), bot_context.clone(), Duration::from_secs(secs)));
+        }
+    }
+
+    if let Some(canary) = canary {
+        let client = client.clone();
+        let bot_context = bot_context.clone();
+        let bot_mxid = UserId::parse(&mxid)?;
+        tokio::spawn(async move { canary.run(client, bot_context, bot_mxid).await });
+    }
+    if let Some(directory_watch) = directory_watch {
+        tokio::spawn(directory_watch.run(client.clone(), bot_context.clone()));
+    }
+
     // Actual message handling and sync loop
-    client.add_event_handler(handle_message);
-    client.sync(SyncSettings::default().token(sync_response.next_batch)).await?;
+    client.add_event_handler(|event: OriginalSyncRoomMessageEvent, room: Room, bot_context: Ctx<BotContext>| {
+        let event_id = Some(event.event_id.clone());
+        panics::guard("message", room.clone(), event_id, bot_context.0.clone(), handle_message(event, room, bot_context))
+    });
+    client.add_event_handler(|event: OriginalSyncRoomMessageEvent, room: Room, bot_context: Ctx<BotContext>| {
+        let event_id = Some(event.event_id.clone());
+        panics::guard("echo", room.clone(), event_id, bot_context.0.clone(), handle_own_echo(event, room, bot_context))
+    });
+    client.add_event_handler(|event: OriginalSyncRoomMessageEvent, room: Room, bot_context: Ctx<BotContext>| {
+        let event_id = Some(event.event_id.clone());
+        panics::guard("command", room.clone(), event_id, bot_context.0.clone(), commands::handle_command(event, room, bot_context))
+    });
+    client.add_event_handler(|event: StrippedRoomMemberEvent, room: Room, client: Client, bot_context: Ctx<BotContext>| {
+        panics::guard("invite", room.clone(), None, bot_context.0.clone(), optout::handle_invite(event, room, client, bot_context))
+    });
+    client.add_event_handler(|event: OriginalSyncRoomMessageEvent, room: Room, bot_context: Ctx<BotContext>| {
+        let event_id = Some(event.event_id.clone());
+        panics::guard("opt-out", room.clone(), event_id, bot_context.0.clone(), optout::handle_dm(event, room, bot_context))
+    });
+    client.add_event_handler(|event: OriginalSyncRoomMemberEvent, room: Room, bot_context: Ctx<BotContext>| {
+        let event_id = Some(event.event_id.clone());
+        panics::guard("member", room.clone(), event_id, bot_context.0.clone(), impersonation::handle_member_event(event, room, bot_context))
+    });
+    client.add_event_handler(|event: ToDeviceKeyVerificationRequestEvent, client: Client, bot_context: Ctx<BotContext>| {
+        let sender = event.sender.clone();
+        panics::guard_to_device("verification", client.clone(), sender, bot_context.0.clone(), verification::handle_request(event, client, bot_context))
+    });
+    client.add_event_handler(|event: ToDeviceKeyVerificationStartEvent, client: Client, bot_context: Ctx<BotContext>| {
+        let sender = event.sender.clone();
+        panics::guard_to_device("verification", client.clone(), sender, bot_context.0.clone(), verification::handle_start(event, client, bot_context))
+    });
+    client.add_event_handler(|event: ToDeviceKeyVerificationKeyEvent, client: Client, bot_context: Ctx<BotContext>| {
+        let sender = event.sender.clone();
+        panics::guard_to_device("verification", client.clone(), sender, bot_context.0.clone(), verification::handle_key(event, client, bot_context))
+    });
+    client.add_event_handler(|event: ToDeviceKeyVerificationDoneEvent, client: Client, bot_context: Ctx<BotContext>| {
+        let sender = event.sender.clone();
+        panics::guard_to_device("verification", client.clone(), sender, bot_context.0.clone(), verification::handle_done(event, client, bot_context))
+    });
+    if let Err(e) = client.sync(SyncSettings::default().token(sync_response.next_batch)).await {
+        telemetry::capture_error(&format!("Sync failed: {e}"), None, None);
+        return Err(e.into());
+    }
 
     Ok(())
 }
 
+/// HTTP client for the homeserver connection and all helper services, optionally bound to the
+/// configured local address or network interface.
+fn build_http_client(config: &Config) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .min_tls_version(reqwest::tls::Version::TLS_1_2);
+    if let Ok(address) = config.get::<String>("network.bind_address") {
+        let address = address.parse::<IpAddr>()
+            .with_context(|| format!("Invalid network.bind_address {address}"))?;
+        builder = builder.local_address(address);
+    }
+    if let Ok(interface) = config.get::<String>("network.interface") {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        {
+            builder = builder.interface(&interface);
+        }
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        anyhow::bail!("network.interface {interface} is not supported on this platform, use network.bind_address");
+    }
+    Ok(builder.build()?)
+}
+
+/// Compile all patterns that count as a ping into a single automaton, so a message body
+/// only needs to be scanned once no matter how many patterns are configured.
+fn build_mention_matcher<P: AsRef<str>>(patterns: &[P]) -> anyhow::Result<AhoCorasick> {
+    Ok(AhoCorasick::new(patterns.iter().map(AsRef::as_ref))?)
+}
+
 async fn handle_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
@@ -142,72 +440,435 @@ async fn handle_message(
     if room.state() != RoomState::Joined {
         return;
     }
-    if event.sender == room.own_user_id() {
-        return;
+    let check = check_ping(&event, room.room_id(), room.own_user_id(), &bot_context);
+    if (check.is_watched || check.is_test) && !check.own_message && check.supported_type && check.too_old {
+        info!("Ignore message in the past: {} in {}", event.event_id, room.room_id());
+        return
     }
-    let is_watched = bot_context.watched_rooms.clone().into_iter().any(|r| r == room.room_id());
-    let is_test = !is_watched && bot_context.watched_test_rooms.clone().into_iter().any(|r| r == room.room_id());
-    if !is_watched && !is_test {
+    if !check.is_ping() {
         return;
     }
-    let MessageType::Text(text_content) = event.clone().content.msgtype else {
+    let federation_lag = federation_lag(&event, room.own_user_id(), &bot_context);
+
+    // Limit concurrent deliveries. Waiting for a free slot holds up the sync loop, so a mention
+    // storm slows down event processing instead of flooding the homeserver.
+    if bot_context.delivery_permits.available_permits() == 0 {
+        debug!("All delivery slots busy, waiting before reporting {} in {}", event.event_id, room.room_id());
+    }
+    let Ok(permit) = bot_context.delivery_permits.clone().acquire_owned().await else {
+        error!("Delivery queue closed, dropping report for {} in {}", event.event_id, room.room_id());
         return;
     };
 
-    if u128::from(event.origin_server_ts.0) < bot_context.0.launched_ts - 10_000 {
-        info!("Ignore message in the past: {} in {}", event.event_id, room.room_id());
-        return
+    // Deliver in the background, so the sync loop keeps running (and with it the echoes of sent
+    // reports, commands and other pings) while reports are being sent
+    // Canaries are expected to repeat, and need a fresh report each time to pass
+    let reservation = bot_context.repeat_tracker.as_ref()
+        .filter(|_| !check.is_canary)
+        .map(|tracker| tracker.reserve(event.sender.clone(), room.room_id().to_owned()));
+    let event_id = Some(event.event_id.clone());
+    let bot_context = bot_context.0.clone();
+    let delivery = deliver_report(event, room.clone(), check.is_canary, check.is_test, federation_lag, reservation, bot_context.clone());
+    tokio::spawn(panics::guard("delivery", room, event_id, bot_context, async move {
+        delivery.await;
+        drop(permit);
+    }));
+}
+
+/// Report a ping to all report rooms and acknowledge it in the original room
+async fn deliver_report(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    is_canary: bool,
+    is_test: bool,
+    federation_lag: Option<Duration>,
+    reservation: Option<Reservation>,
+    bot_context: BotContext,
+) {
+    let Some((body, formatted_body)) = message_text(&event.content.msgtype) else {
+        return;
+    };
+    let orig_sender = &event.sender;
+    let orig_url = room.room_id().matrix_to_event_uri(event.event_id.clone());
+
+    // Held until this ping is reported, so later pings of the same sender can be appended to it
+    let mut recent = match reservation {
+        Some(reservation) => Some(reservation.acquire().await),
+        None => None,
+    };
+    let collapsed = match recent.as_mut().filter(|reports| !reports.is_empty()) {
+        Some(reports) => repeats::append_to_recent(&room.client(), reports, &orig_url.to_string()).await,
+        None => false,
+    };
+
+    let reported = if collapsed {
+        true
+    } else {
+        let details = report_details(&room, &event.event_id, orig_sender, body, formatted_body, federation_lag, &bot_context).await;
+        let attachment = Some(&event.content.msgtype)
+            .filter(|m| bot_context.mirror_attachments && !matches!(m, MessageType::Text(_)))
+            .filter(|_| !bot_context.is_opted_out(orig_sender));
+        let sent_reports = send_reports(&room, orig_sender, &orig_url, is_test, &details, attachment, &bot_context).await;
+        let reported = !sent_reports.is_empty();
+        if let Some(recent) = recent.as_mut().filter(|_| reported) {
+            **recent = sent_reports;
+        }
+        reported
+    };
+    drop(recent);
+    if reported && is_canary {
+        if let Some(canary) = &bot_context.canary {
+            canary.reported(body);
+        }
+    }
+    if reported {
+        // Signal we reported it
+        bot_context.acknowledger.ack(&room, orig_sender, event.event_id.clone(), event.content.relates_to.as_ref()).await;
+    } else {
+        error!("Failed to report to any room, not sending any ack reaction");
+        telemetry::capture_error(
+            &format!("Failed to report ping from {orig_sender} to any report room"),
+            Some(room.room_id()),
+            Some(&event.event_id),
+        );
+    }
+}
+
+async fn handle_own_echo(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    bot_context: Ctx<BotContext>,
+) {
+    if event.sender != room.own_user_id() {
+        return;
+    }
+    if let Some(echo_watcher) = &bot_context.echo_watcher {
+        echo_watcher.received(event.event_id);
+    }
+}
+
+/// Why a message was or wasn't considered a ping of the bot
+struct PingCheck {
+    is_watched: bool,
+    is_test: bool,
+    own_message: bool,
+    is_canary: bool,
+    supported_type: bool,
+    too_old: bool,
+    body_match: bool,
+    formatted_match: bool,
+    mentions_match: bool,
+}
+
+impl PingCheck {
+    fn is_ping(&self) -> bool {
+        (self.is_watched || self.is_test) &&
+            !self.own_message &&
+            self.supported_type &&
+            !self.too_old &&
+            (self.body_match || self.formatted_match || self.mentions_match)
+    }
+}
+
+fn check_ping(
+    event: &OriginalSyncRoomMessageEvent,
+    room_id: &RoomId,
+    own_user_id: &UserId,
+    bot_context: &BotContext,
+) -> PingCheck {
+    let is_watched = bot_context.watched_rooms.iter().any(|r| r == room_id);
+    let is_test = !is_watched && bot_context.watched_test_rooms.iter().any(|r| r == room_id);
+    let text = message_text(&event.content.msgtype);
+    let matcher = &bot_context.mention_matcher;
+    let is_canary = bot_context.canary.as_ref().is_some_and(|canary| {
+        text.is_some_and(|(body, _)| canary.is_canary(room_id, &event.sender, own_user_id, body))
+    });
+    PingCheck {
+        is_watched,
+        is_test,
+        own_message: event.sender == own_user_id && !is_canary,
+        is_canary,
+        supported_type: text.is_some(),
+        too_old: u128::from(event.origin_server_ts.0) < bot_context.launched_ts.saturating_sub(bot_context.grace_period_ms),
+        body_match: text.is_some_and(|(body, _)| matcher.is_match(body)),
+        formatted_match: text.and_then(|(_, formatted)| formatted).is_some_and(|f| matcher.is_match(&f.body)),
+        mentions_match: event.content.mentions.as_ref().is_some_and(|m| m.user_ids.contains(own_user_id)),
+    }
+}
+
+/// The text of a message that may ping the bot: the body of text messages, or the caption of
+/// media messages. None for message types that are not reported.
+fn message_text(msgtype: &MessageType) -> Option<(&str, Option<&FormattedBody>)> {
+    match msgtype {
+        MessageType::Text(c) => Some((&c.body, c.formatted.as_ref())),
+        MessageType::Image(c) => Some((c.caption().unwrap_or_default(), c.formatted_caption())),
+        MessageType::File(c) => Some((c.caption().unwrap_or_default(), c.formatted_caption())),
+        MessageType::Video(c) => Some((c.caption().unwrap_or_default(), c.formatted_caption())),
+        MessageType::Audio(c) => Some((c.caption().unwrap_or_default(), c.formatted_caption())),
+        _ => None,
+    }
+}
+
+/// How much later than its origin timestamp a message from a remote server arrived here,
+/// if that's more than the configured threshold. Uses the age our homeserver computed, so this
+/// doesn't depend on the local clock.
+fn federation_lag(event: &OriginalSyncRoomMessageEvent, own_user_id: &UserId, bot_context: &BotContext) -> Option<Duration> {
+    let threshold = bot_context.federation_lag_threshold?;
+    if event.sender.server_name() == own_user_id.server_name() {
+        return None;
+    }
+    let age = u64::try_from(i64::from(event.unsigned.age?)).ok()?;
+    let lag = Duration::from_millis(age);
+    (lag >= threshold).then_some(lag)
+}
+
+/// Collect the optional extra information for a report, each part starting with an empty line
+async fn report_details(
+    room: &Room,
+    event_id: &EventId,
+    sender: &UserId,
+    body: &str,
+    formatted_body: Option<&FormattedBody>,
+    federation_lag: Option<Duration>,
+    bot_context: &BotContext,
+) -> String {
+    let orig_url = room.room_id().matrix_to_event_uri(event_id);
+    let mut details = String::new();
+    if let Some(lag) = federation_lag {
+        details.push_str(&format!(
+            "\n\nNote: this message was delivered {} minutes late via federation.",
+            lag.as_secs() / 60,
+        ));
+    }
+    if bot_context.context_messages > 0 {
+        if let Some(context) = context::preceding_messages(room, event_id, bot_context.context_messages, |user_id| bot_context.is_opted_out(user_id)).await {
+            details.push_str(&format!("\n\nPreceding messages:\n\n{context}"));
+        }
     }
+    if bot_context.is_opted_out(sender) {
+        details.push_str("\n\nThe sender opted out of having their messages quoted in reports.");
+        return details;
+    }
+    if let Some(url_inspector) = &bot_context.url_inspector {
+        let formatted_body = formatted_body.map(|f| f.body.as_str());
+        if let Some(links) = url_inspector.describe(&room.client(), body, formatted_body).await {
+            details.push_str(&format!("\n\nLinks:\n\n{links}"));
+        }
+    }
+    // Both run at the same time, and share the helper slots with concurrent deliveries
+    let summary = async {
+        let summarizer = bot_context.summarizer.as_ref()?;
+        let _permit = bot_context.helper_permits.acquire().await;
+        match summarizer.summarize(&bot_context.http_client, body).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                error!("Failed to summarize message at {orig_url}: {e}");
+                None
+            }
+        }
+    };
+    let translation = async {
+        let translator = bot_context.translator.as_ref().filter(|_| !body.trim().is_empty())?;
+        let _permit = bot_context.helper_permits.acquire().await;
+        match translator.translate(&bot_context.http_client, body).await {
+            Ok(translation) => translation,
+            Err(e) => {
+                error!("Failed to translate message at {orig_url}: {e}");
+                None
+            }
+        }
+    };
+    let (summary, translation) = tokio::join!(summary, translation);
+    if let Some(summary) = summary {
+        details.push_str(&format!(
+            "\n\nSummary:\n\n> {}",
+            markdown::escape(&summary).replace('\n', "\n> "),
+        ));
+    }
+    if let Some(translation) = translation {
+        details.push_str(&format!(
+            "\n\nTranslation (from {}):\n\n> {}",
+            translation.source_language,
+            markdown::escape(&translation.text).replace('\n', "\n> "),
+        ));
+    }
+    details
+}
+
+/// Post a new report into every report room, returns the reports that were sent successfully
+async fn send_reports(
+    room: &Room,
+    orig_sender: &UserId,
+    orig_url: &MatrixToUri,
+    is_test: bool,
+    details: &str,
+    attachment: Option<&MessageType>,
+    bot_context: &BotContext,
+) -> Vec<SentReport> {
+    let mut sent_reports = Vec::new();
+    for report_room_id in bot_context.report_rooms.iter() {
+        let Some(report_room) = get_or_join_report_room(&room.client(), report_room_id, bot_context).await else {
+            error!("Failed to retrieve or join report room {report_room_id}");
+            continue;
+        };
+
+        // Rather find out about missing permissions now than from an opaque error response
+        let own_user_id = report_room.own_user_id();
+        let (can_send, can_ping_room) = match report_room.power_levels().await {
+            Ok(power_levels) => (
+                power_levels.user_can_send_message(own_user_id, MessageLikeEventType::RoomMessage),
+                power_levels.user_can_trigger_room_notification(own_user_id),
+            ),
+            Err(e) => {
+                warn!("Failed to check my permissions in {report_room_id}, trying to report anyway: {e}");
+                (true, true)
+            }
+        };
+        if !can_send {
+            error!("Not allowed to send messages in report room {report_room_id}, not reporting message from {orig_sender} at {orig_url} there");
+            continue;
+        }
+        let room_ping = !is_test && can_ping_room;
+        let msg = if is_test {
+            format!("I was pinged by {orig_sender} at {orig_url}, which is a test room so I won't bother you with a room ping this time{details}")
+        } else if room_ping {
+            format!("@room: I was pinged by {orig_sender} at {orig_url}{details}")
+        } else {
+            warn!("Not allowed to ping the room in report room {report_room_id}, reporting without room ping");
+            format!("I was pinged by {orig_sender} at {orig_url} (I'm not allowed to ping the room here){details}")
+        };
 
-    let bot_mxid = bot_context.0.bot_mxid;
-    let bot_mxid_escaped = bot_context.0.bot_mxid_http_escaped;
-
-    if text_content.body.contains(&bot_mxid) ||
-        text_content.formatted.map(|f|
-            f.body.contains(&bot_mxid) || f.body.contains(&bot_mxid_escaped)
-        ).unwrap_or(false) ||
-        event.content.mentions.map(|m|
-            m.user_ids.contains(room.own_user_id())
-        ).unwrap_or(false)
-    {
-        let orig_sender = event.sender;
-        let orig_url = room.room_id().matrix_to_event_uri(event.event_id.clone());
-        let mut reported = false;
-        for report_room_id in bot_context.0.report_rooms {
-            let report_room = room.client().get_room(&report_room_id);
-            match report_room {
-                None => error!("Failed to retrieve report room {report_room_id} from client"),
-                Some(report_room) => {
-                    let content = if is_test {
-                        let msg = format!("I was pinged by {orig_sender} at {orig_url}, which is a test room so I won't bother you with a room ping this time");
-                        RoomMessageEventContent::notice_markdown(msg)
-                    } else {
-                        let msg = format!("@room: I was pinged by {orig_sender} at {orig_url}");
-                        RoomMessageEventContent::text_markdown(msg)
-                            .add_mentions(Mentions::with_room_mention())
-                    };
-                    if let Err(e) = report_room.send(content).await {
-                        error!("Failed to report message from {} at {}: {}", orig_sender, orig_url, e);
-                    } else {
+        // Reports with lots of details may exceed the event size limit, continue those in notices
+        // Assume the smaller limit for encrypted rooms if in doubt
+        let encrypted = report_room.latest_encryption_state().await.map_or(true, |state| state.is_encrypted());
+        let chunks = limits::split_to_fit(&msg, |chunk| limits::fits(&report_content(chunk, is_test, room_ping), encrypted));
+        let mut sent_report = None;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let content = if i == 0 {
+                report_content(&chunk, is_test, room_ping)
+            } else {
+                RoomMessageEventContent::notice_markdown(&chunk)
+            };
+            match report_room.send(content.clone()).await {
+                Err(e) if i == 0 => {
+                    error!("Failed to report message from {} at {}: {}", orig_sender, orig_url, e);
+                    break;
+                }
+                Err(e) => error!("Failed to send part {} of report for {orig_url} to {report_room_id}: {e}", i + 1),
+                Ok(response) => {
+                    if i == 0 {
                         info!("Successfully reported message from {} at {} to {}", orig_sender, orig_url, report_room_id);
-                        reported = true;
+                        sent_report = Some(SentReport {
+                            room_id: report_room_id.clone(),
+                            event_id: response.event_id.clone(),
+                            body: chunk,
+                            is_test,
+                            room_ping,
+                        });
+                    }
+                    if let Some(echo_watcher) = &bot_context.echo_watcher {
+                        echo_watcher.confirm(report_room.clone(), response.event_id, content);
                     }
                 }
             }
         }
-        if reported {
-            // Send reaction to signal we reported it
-            let reaction = ReactionEventContent::new(
-                Annotation::new(
-                    event.event_id,
-                    "📨".to_owned(),
-                )
-            );
-            if let Err(e) = room.send(reaction).await {
-                error!("Failed to send ack reaction: {}", e);
+        let Some(sent_report) = sent_report else {
+            continue;
+        };
+        if let Some(attachment) = attachment {
+            // Reuses the uploaded media, so this works without downloading it
+            // Without m.mentions, the caption could trigger the legacy push rules in the report room
+            let mirror = RoomMessageEventContent::new(attachment.clone()).add_mentions(Mentions::new());
+            if let Err(e) = report_room.send(mirror).await {
+                error!("Failed to mirror attachment of {orig_url} to {report_room_id}: {e}");
             }
+        }
+        sent_reports.push(sent_report);
+    }
+    sent_reports
+}
+
+/// The content for (the first part of) a report: a notice for test rooms, otherwise a text
+/// message that pings the room if we are allowed to
+fn report_content(msg: &str, is_test: bool, room_ping: bool) -> RoomMessageEventContent {
+    if is_test {
+        RoomMessageEventContent::notice_markdown(msg)
+    } else if room_ping {
+        RoomMessageEventContent::text_markdown(msg)
+            .add_mentions(Mentions::with_room_mention())
+    } else {
+        // Reports quote user-provided text, which must not trigger the legacy push rules
+        RoomMessageEventContent::text_markdown(msg)
+            .add_mentions(Mentions::new())
+    }
+}
+
+/// Look up a report room, joining it first if the bot is not in there (anymore)
+async fn get_or_join_report_room(client: &Client, room_id: &RoomId, bot_context: &BotContext) -> Option<Room> {
+    if let Some(room) = client.get_room(room_id) {
+        if room.state() == RoomState::Joined {
+            return Some(room);
+        }
+    }
+    let mut via = bot_context.report_room_via_servers.clone();
+    if let Some(server) = room_id.server_name() {
+        if !via.iter().any(|s| s == server) {
+            via.push(server.to_owned());
+        }
+    }
+    info!("Not joined to report room {room_id}, trying to join via {via:?}");
+    match client.join_room_by_id_or_alias(room_id.into(), &via).await {
+        Ok(room) => {
+            info!("Joined report room {room_id}");
+            Some(room)
+        }
+        Err(e) => {
+            error!("Failed to join report room {room_id}: {e}");
+            None
+        }
+    }
+}
+
+/// Our homeserver's current time according to the timeline events of a sync response: the
+/// origin_server_ts plus the age the homeserver computed when serving the event. Only events
+/// sent by users of our own homeserver count, remote servers set their own timestamps, which may
+/// be off by any amount.
+fn server_time(sync_response: &SyncResponse, own_server: &ServerName) -> Option<u128> {
+    sync_response.rooms.joined.values()
+        .flat_map(|room| room.timeline.events.iter())
+        .filter(|event| {
+            event.raw().get_field::<OwnedUserId>("sender").ok().flatten()
+                .is_some_and(|sender| sender.server_name() == own_server)
+        })
+        .filter_map(|event| {
+            let ts = event.raw().get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts").ok().flatten()?;
+            let age = event.raw().get_field::<EventAge>("unsigned").ok().flatten()?.age?;
+            Some(u128::from(ts.0) + u128::from(age))
+        })
+        .max()
+}
+
+#[derive(Deserialize)]
+struct EventAge {
+    age: Option<u64>,
+}
+
+/// Send a message from the bot itself (not a report) to all report rooms.
+/// With `urgent` the message is sent with a room ping, otherwise as notice.
+async fn notify_report_rooms(client: &Client, bot_context: &BotContext, msg: &str, urgent: bool) {
+    for report_room_id in bot_context.report_rooms.iter() {
+        let Some(report_room) = get_or_join_report_room(client, report_room_id, bot_context).await else {
+            error!("Failed to retrieve or join report room {report_room_id}");
+            continue;
+        };
+        let content = if urgent {
+            RoomMessageEventContent::text_markdown(format!("@room: {msg}"))
+                .add_mentions(Mentions::with_room_mention())
         } else {
-            error!("Failed to report to any room, not sending any ack reaction");
+            RoomMessageEventContent::notice_markdown(msg)
+        };
+        if let Err(e) = report_room.send(content).await {
+            error!("Failed to send notification to {report_room_id}: {e}");
         }
     }
 }
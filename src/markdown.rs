@@ -0,0 +1,45 @@
+/// Escape user-provided text so it's rendered verbatim when embedded into a markdown report
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_{}[]()<>#+-.!|~@".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Shorten a message body into a single line of at most `max_chars` characters
+pub fn snippet(body: &str, max_chars: usize) -> String {
+    let single_line = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    if single_line.chars().count() <= max_chars {
+        single_line
+    } else {
+        let mut shortened: String = single_line.chars().take(max_chars).collect();
+        shortened.push('…');
+        shortened
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_keeps_markup_verbatim() {
+        assert_eq!(escape("*hi* [x](y) @room"), "\\*hi\\* \\[x\\]\\(y\\) \\@room");
+        assert_eq!(escape("plain text"), "plain text");
+    }
+
+    #[test]
+    fn snippet_collapses_whitespace() {
+        assert_eq!(snippet("  two\n\nlines\there ", 100), "two lines here");
+    }
+
+    #[test]
+    fn snippet_shortens_by_chars() {
+        assert_eq!(snippet("äöüß", 4), "äöüß");
+        assert_eq!(snippet("äöüß", 2), "äö…");
+    }
+}
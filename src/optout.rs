@@ -0,0 +1,126 @@
+use log::{error, info};
+use matrix_sdk::{
+    Client, Room, RoomState,
+    event_handler::Ctx,
+    ruma::{
+        events::room::{
+            member::StrippedRoomMemberEvent,
+            message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        },
+        OwnedUserId, UserId,
+    },
+};
+use std::{collections::HashSet, path::PathBuf, sync::Mutex};
+use tokio::fs;
+
+use crate::BotContext;
+
+/// Users who asked for their messages to never be quoted or mirrored in reports,
+/// persisted as JSON list in the data directory
+pub struct OptOuts {
+    path: PathBuf,
+    users: Mutex<HashSet<OwnedUserId>>,
+    // Serializes writes of the file
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl OptOuts {
+    pub async fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let users = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path).await?)?
+        } else {
+            HashSet::new()
+        };
+        Ok(OptOuts {
+            path,
+            users: Mutex::new(users),
+            write_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn contains(&self, user_id: &UserId) -> bool {
+        self.users.lock().unwrap().contains(user_id)
+    }
+
+    /// Update and persist the preference of a user, returns whether anything changed
+    async fn set(&self, user_id: &UserId, opted_out: bool) -> anyhow::Result<bool> {
+        let _write_lock = self.write_lock.lock().await;
+        let serialized = {
+            let mut users = self.users.lock().unwrap();
+            let changed = if opted_out {
+                users.insert(user_id.to_owned())
+            } else {
+                users.remove(user_id)
+            };
+            if !changed {
+                return Ok(false);
+            }
+            serde_json::to_string(&*users)?
+        };
+        // Write a temporary file next to it and move that over, so a crash mid-write can't leave
+        // a truncated file that fails to load on the next start
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serialized).await?;
+        fs::rename(&tmp_path, &self.path).await?;
+        Ok(true)
+    }
+}
+
+/// Accept DM invites, so users can reach the opt-out commands
+pub async fn handle_invite(
+    event: StrippedRoomMemberEvent,
+    room: Room,
+    client: Client,
+    bot_context: Ctx<BotContext>,
+) {
+    if bot_context.optouts.is_none() || room.state() != RoomState::Invited {
+        return;
+    }
+    if client.user_id() != Some(&*event.state_key) || event.content.is_direct != Some(true) {
+        return;
+    }
+    info!("Accepting DM invite from {} to {}", event.sender, room.room_id());
+    if let Err(e) = room.join().await {
+        error!("Failed to accept DM invite to {}: {e}", room.room_id());
+    }
+}
+
+/// Handle !optout and !optin sent to the bot in a DM
+pub async fn handle_dm(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    bot_context: Ctx<BotContext>,
+) {
+    let Some(optouts) = &bot_context.optouts else {
+        return;
+    };
+    if event.sender == room.own_user_id() || !room.is_direct().await.unwrap_or(false) {
+        return;
+    }
+    let MessageType::Text(text_content) = &event.content.msgtype else {
+        return;
+    };
+    let opted_out = match text_content.body.trim() {
+        "!optout" => true,
+        "!optin" => false,
+        _ => return,
+    };
+    let reply = match optouts.set(&event.sender, opted_out).await {
+        Ok(_) if opted_out => {
+            info!("{} opted out of message quoting", event.sender);
+            "Done: when you ping me, reports will only link to your message and never quote or mirror it. Send !optin to undo."
+        }
+        Ok(_) => {
+            info!("{} opted in to message quoting again", event.sender);
+            "Done: reports may quote your messages again."
+        }
+        Err(e) => {
+            error!("Failed to save opt-out preference of {}: {e}", event.sender);
+            "Sorry, I failed to save your preference, please try again later."
+        }
+    };
+    if let Err(e) = room.send(RoomMessageEventContent::notice_plain(reply)).await {
+        error!("Failed to reply to {} in {}: {e}", event.sender, room.room_id());
+    }
+}
@@ -0,0 +1,70 @@
+use log::error;
+use matrix_sdk::{
+    ruma::{OwnedEventId, OwnedUserId},
+    Client, Room,
+};
+use std::{any::Any, future::Future, sync::atomic::Ordering};
+
+use crate::{notify_report_rooms, BotContext};
+
+/// Run an event handler in its own task, so a panic in it only loses that one event instead of
+/// taking down the sync loop. Panics are logged with the event they happened for and announced in
+/// the report rooms; Sentry already receives them via its panic integration.
+pub async fn guard(
+    handler: &str,
+    room: Room,
+    event_id: Option<OwnedEventId>,
+    bot_context: BotContext,
+    future: impl Future<Output = ()> + Send + 'static,
+) {
+    let event = match event_id {
+        Some(event_id) => room.room_id().matrix_to_event_uri(event_id).to_string(),
+        None => format!("an event in {}", room.room_id()),
+    };
+    run_guarded(handler, &room.client(), &event, &bot_context, future).await;
+}
+
+/// Like [`guard`], for to-device events which don't belong to a room.
+pub async fn guard_to_device(
+    handler: &str,
+    client: Client,
+    sender: OwnedUserId,
+    bot_context: BotContext,
+    future: impl Future<Output = ()> + Send + 'static,
+) {
+    let event = format!("a to-device event from {sender}");
+    run_guarded(handler, &client, &event, &bot_context, future).await;
+}
+
+async fn run_guarded(
+    handler: &str,
+    client: &Client,
+    event: &str,
+    bot_context: &BotContext,
+    future: impl Future<Output = ()> + Send + 'static,
+) {
+    let Err(e) = tokio::spawn(future).await else {
+        return;
+    };
+    if !e.is_panic() {
+        return;
+    }
+    let reason = panic_message(e.into_panic());
+    let panics = bot_context.handler_panics.fetch_add(1, Ordering::Relaxed) + 1;
+    error!("Panic in {handler} handler for {event} ({panics} since startup): {reason}");
+    let msg = format!(
+        "I crashed handling {event} and may have missed a ping there, please check it manually. \
+         Error in the {handler} handler: {reason} ({panics} handler crashes since startup)"
+    );
+    notify_report_rooms(client, bot_context, &msg, true).await;
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .unwrap_or_else(|| String::from("unknown panic")),
+    }
+}
@@ -0,0 +1,118 @@
+use log::{error, info};
+use matrix_sdk::{
+    Client,
+    ruma::{
+        events::{
+            room::message::ReplacementMetadata,
+            Mentions,
+        },
+        OwnedEventId, OwnedRoomId, OwnedUserId,
+    },
+};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::{limits, report_content};
+
+/// A report message the bot sent into a report room
+pub struct SentReport {
+    pub room_id: OwnedRoomId,
+    pub event_id: OwnedEventId,
+    pub body: String,
+    pub is_test: bool,
+    pub room_ping: bool,
+}
+
+struct TrackedReport {
+    last_ping: Instant,
+    reports: Arc<Mutex<Vec<SentReport>>>,
+}
+
+/// A delivery's claim on the reports of its sender and watched room. Deliveries for the same
+/// sender and room hold it one after the other, so later pings see the report of the first one.
+pub enum Reservation {
+    /// First ping within the window, nobody else can hold the reports before it's dropped
+    New(OwnedMutexGuard<Vec<SentReport>>),
+    /// Repeated ping, needs to wait for earlier deliveries to finish
+    Repeat(Arc<Mutex<Vec<SentReport>>>),
+}
+
+impl Reservation {
+    /// Wait for earlier deliveries, the returned reports are empty if there are none to append to
+    pub async fn acquire(self) -> OwnedMutexGuard<Vec<SentReport>> {
+        match self {
+            Reservation::New(reports) => reports,
+            Reservation::Repeat(reports) => reports.lock_owned().await,
+        }
+    }
+}
+
+/// Remembers recent reports by sender and watched room, so repeated pings can be appended to the
+/// existing report instead of posting a new one
+pub struct RepeatTracker {
+    window: Duration,
+    tracked: std::sync::Mutex<HashMap<(OwnedUserId, OwnedRoomId), TrackedReport>>,
+}
+
+impl RepeatTracker {
+    pub fn new(window: Duration) -> Self {
+        RepeatTracker {
+            window,
+            tracked: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Claim the reports for a ping before its delivery is started, so pings are handled in the
+    /// order they arrived in
+    pub fn reserve(&self, sender: OwnedUserId, room_id: OwnedRoomId) -> Reservation {
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.retain(|_, t| t.last_ping.elapsed() < self.window);
+        if let Some(recent) = tracked.get_mut(&(sender.clone(), room_id.clone())) {
+            recent.last_ping = Instant::now();
+            return Reservation::Repeat(recent.reports.clone());
+        }
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let guard = reports.clone().try_lock_owned().expect("New lock can't be held by anyone");
+        tracked.insert((sender, room_id), TrackedReport {
+            last_ping: Instant::now(),
+            reports,
+        });
+        Reservation::New(guard)
+    }
+}
+
+/// Edit the reports of a recent ping by the same sender in the same room to mention the new
+/// ping as well. Returns false if none of them could be appended to.
+pub async fn append_to_recent(client: &Client, reports: &mut [SentReport], ping_url: &str) -> bool {
+    let mut appended = false;
+    for report in reports.iter_mut() {
+        let Some(report_room) = client.get_room(&report.room_id) else {
+            error!("Failed to retrieve report room {} from client", report.room_id);
+            continue;
+        };
+        let body = format!("{}\n\n+1 further ping at {ping_url}", report.body);
+        let content = report_content(&body, report.is_test, report.room_ping);
+        // Leave room for the edit fallback, which repeats the body
+        let encrypted = report_room.latest_encryption_state().await.map_or(true, |state| state.is_encrypted());
+        if !limits::fits(&report_content(&format!("{body}\n{body}"), report.is_test, report.room_ping), encrypted) {
+            info!("Report {} in {} is too large to append further pings to", report.event_id, report.room_id);
+            continue;
+        }
+        // Passing the original mentions here avoids pinging the room again for the edit, and
+        // empty ones keep the legacy push rules from firing
+        let mentions = Some(if report.room_ping { Mentions::with_room_mention() } else { Mentions::new() });
+        let content = content.make_replacement(ReplacementMetadata::new(report.event_id.clone(), mentions), None);
+        if let Err(e) = report_room.send(content).await {
+            error!("Failed to append ping {ping_url} to report {} in {}: {e}", report.event_id, report.room_id);
+        } else {
+            info!("Appended ping {ping_url} to report {} in {}", report.event_id, report.room_id);
+            report.body = body;
+            appended = true;
+        }
+    }
+    appended
+}
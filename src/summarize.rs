@@ -0,0 +1,82 @@
+use anyhow::Context;
+use config::Config;
+use std::{process::Stdio, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, time::timeout};
+
+/// Where long messages get sent to for summarization
+pub enum Backend {
+    /// POST the message as plain text, the response body is the summary
+    Http(String),
+    /// Pipe the message into stdin of a program, its stdout is the summary
+    Command(Vec<String>),
+}
+
+pub struct Summarizer {
+    backend: Backend,
+    min_length: usize,
+    timeout: Duration,
+}
+
+impl Summarizer {
+    /// Read the optional `summarizer` config section, returns None if no summarizer is configured
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let backend = if let Ok(url) = config.get::<String>("summarizer.url") {
+            Backend::Http(url)
+        } else if let Ok(command) = config.get::<Vec<String>>("summarizer.command") {
+            if command.is_empty() {
+                anyhow::bail!("summarizer.command needs at least the program to run");
+            }
+            Backend::Command(command)
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Summarizer {
+            backend,
+            min_length: config.get::<usize>("summarizer.min_length").unwrap_or(1000),
+            timeout: Duration::from_secs(config.get::<u64>("summarizer.timeout_secs").unwrap_or(30)),
+        }))
+    }
+
+    /// Summarize `text` if it's long enough to be worth it
+    pub async fn summarize(&self, http: &reqwest::Client, text: &str) -> anyhow::Result<Option<String>> {
+        if text.chars().count() < self.min_length {
+            return Ok(None);
+        }
+        let summary = timeout(self.timeout, self.run(http, text)).await
+            .context("Summarizer timed out")??;
+        let summary = summary.trim();
+        if summary.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(summary.to_owned()))
+    }
+
+    async fn run(&self, http: &reqwest::Client, text: &str) -> anyhow::Result<String> {
+        match &self.backend {
+            Backend::Http(url) => {
+                Ok(http.post(url)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(text.to_owned())
+                    .send().await?
+                    .error_for_status()?
+                    .text().await?)
+            }
+            Backend::Command(command) => {
+                let mut child = Command::new(&command[0])
+                    .args(&command[1..])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()?;
+                let mut stdin = child.stdin.take().context("Failed to open summarizer stdin")?;
+                stdin.write_all(text.as_bytes()).await?;
+                drop(stdin);
+                let output = child.wait_with_output().await?;
+                if !output.status.success() {
+                    anyhow::bail!("Summarizer exited with {}", output.status);
+                }
+                Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+            }
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use config::Config;
+use log::info;
+use matrix_sdk::ruma::{EventId, RoomId};
+use sentry::{ClientInitGuard, ClientOptions, Level};
+
+/// Set up Sentry (or a compatible service) if `sentry.dsn` is configured. Panics are captured
+/// automatically once this is initialized, the returned guard needs to be kept alive until exit.
+pub fn init(config: &Config) -> Option<ClientInitGuard> {
+    let dsn = config.get::<String>("sentry.dsn").ok()?;
+    let guard = sentry::init((dsn, ClientOptions {
+        release: sentry::release_name!(),
+        environment: config.get::<String>("sentry.environment").ok().map(Into::into),
+        ..Default::default()
+    }));
+    if guard.is_enabled() {
+        info!("Sentry error reporting enabled");
+    }
+    Some(guard)
+}
+
+/// Send an error to Sentry, tagged with the room and event it happened for.
+/// This is a no-op if Sentry is not configured.
+pub fn capture_error(message: &str, room_id: Option<&RoomId>, event_id: Option<&EventId>) {
+    sentry::with_scope(|scope| {
+        if let Some(room_id) = room_id {
+            scope.set_tag("room_id", room_id);
+        }
+        if let Some(event_id) = event_id {
+            scope.set_tag("event_id", event_id);
+        }
+    }, || sentry::capture_message(message, Level::Error));
+}
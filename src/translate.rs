@@ -0,0 +1,158 @@
+use anyhow::Context;
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// HTTP translation services a report can be translated with
+pub enum Backend {
+    LibreTranslate,
+    DeepL,
+}
+
+pub struct Translator {
+    backend: Backend,
+    url: String,
+    api_key: Option<String>,
+    target_language: String,
+    timeout: Duration,
+}
+
+/// A translated message, along with the language it was detected to be written in
+pub struct Translation {
+    pub source_language: String,
+    pub text: String,
+}
+
+#[derive(Serialize)]
+struct LibreTranslateRequest<'a> {
+    q: &'a str,
+    source: &'a str,
+    target: &'a str,
+    format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct LibreTranslateResponse {
+    #[serde(rename = "translatedText")]
+    translated_text: String,
+    #[serde(rename = "detectedLanguage")]
+    detected_language: Option<LibreTranslateDetectedLanguage>,
+}
+
+#[derive(Deserialize)]
+struct LibreTranslateDetectedLanguage {
+    language: String,
+}
+
+#[derive(Serialize)]
+struct DeepLRequest<'a> {
+    text: [&'a str; 1],
+    target_lang: &'a str,
+}
+
+#[derive(Deserialize)]
+struct DeepLResponse {
+    translations: Vec<DeepLTranslation>,
+}
+
+#[derive(Deserialize)]
+struct DeepLTranslation {
+    detected_source_language: String,
+    text: String,
+}
+
+impl Translator {
+    /// Read the optional `translation` config section, returns None if translation is not enabled
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Ok(backend) = config.get::<String>("translation.backend") else {
+            return Ok(None);
+        };
+        let backend = match backend.to_lowercase().as_str() {
+            "libretranslate" => Backend::LibreTranslate,
+            "deepl" => Backend::DeepL,
+            other => anyhow::bail!("Unknown translation.backend {other}, expected libretranslate or deepl"),
+        };
+        let url = match config.get::<String>("translation.url") {
+            Ok(url) => url,
+            Err(_) => match backend {
+                Backend::DeepL => String::from("https://api-free.deepl.com"),
+                Backend::LibreTranslate => anyhow::bail!("Missing translation.url in config"),
+            },
+        };
+        let api_key = config.get::<String>("translation.api_key").ok();
+        if matches!(backend, Backend::DeepL) && api_key.is_none() {
+            anyhow::bail!("Missing translation.api_key in config, which is required for DeepL");
+        }
+        Ok(Some(Translator {
+            backend,
+            url: url.trim_end_matches('/').to_owned(),
+            api_key,
+            target_language: config.get::<String>("translation.target_language").unwrap_or(String::from("en")),
+            timeout: Duration::from_secs(config.get::<u64>("translation.timeout_secs").unwrap_or(30)),
+        }))
+    }
+
+    /// Translate `text` into the target language, returns None if it's already written in it
+    pub async fn translate(&self, http: &reqwest::Client, text: &str) -> anyhow::Result<Option<Translation>> {
+        let translation = timeout(self.timeout, self.run(http, text)).await
+            .context("Translation timed out")??;
+        if self.is_target_language(&translation.source_language) {
+            return Ok(None);
+        }
+        Ok(Some(translation))
+    }
+
+    async fn run(&self, http: &reqwest::Client, text: &str) -> anyhow::Result<Translation> {
+        let translation = match self.backend {
+            Backend::LibreTranslate => {
+                let request = LibreTranslateRequest {
+                    q: text,
+                    source: "auto",
+                    target: &self.target_language,
+                    format: "text",
+                    api_key: self.api_key.as_deref(),
+                };
+                let response: LibreTranslateResponse = http.post(format!("{}/translate", self.url))
+                    .json(&request)
+                    .send().await?
+                    .error_for_status()?
+                    .json().await?;
+                Translation {
+                    source_language: response.detected_language
+                        .map(|l| l.language)
+                        .unwrap_or_default(),
+                    text: response.translated_text,
+                }
+            }
+            Backend::DeepL => {
+                let target_language = self.target_language.to_uppercase();
+                let request = DeepLRequest {
+                    text: [text],
+                    target_lang: &target_language,
+                };
+                let response: DeepLResponse = http.post(format!("{}/v2/translate", self.url))
+                    .header("Authorization", format!("DeepL-Auth-Key {}", self.api_key.as_deref().unwrap_or_default()))
+                    .json(&request)
+                    .send().await?
+                    .error_for_status()?
+                    .json().await?;
+                let translation = response.translations.into_iter().next()
+                    .context("DeepL returned no translation")?;
+                Translation {
+                    source_language: translation.detected_source_language.to_lowercase(),
+                    text: translation.text,
+                }
+            }
+        };
+        Ok(translation)
+    }
+
+    fn is_target_language(&self, language: &str) -> bool {
+        // Compare primary language subtags only, so e.g. "en" matches a target of "en-US"
+        let primary = |l: &str| l.split(['-', '_']).next().unwrap_or_default().to_lowercase();
+        primary(language) == primary(&self.target_language)
+    }
+}
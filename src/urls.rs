@@ -0,0 +1,180 @@
+use config::Config;
+use log::warn;
+use matrix_sdk::{
+    Client,
+    ruma::api::client::authenticated_media::get_media_preview,
+};
+use serde::Deserialize;
+use url::{Position, Url};
+
+use crate::markdown;
+
+const PREVIEW_SNIPPET_LENGTH: usize = 200;
+/// Links listed per report, so a message full of links doesn't stall its delivery
+const MAX_LINKS: usize = 10;
+
+/// Lists links of a ping in the report, flagging blocklisted ones
+pub struct UrlInspector {
+    blocklist: Vec<String>,
+    preview: bool,
+}
+
+#[derive(Deserialize)]
+struct Preview {
+    #[serde(rename = "og:title")]
+    title: Option<String>,
+    #[serde(rename = "og:description")]
+    description: Option<String>,
+}
+
+impl UrlInspector {
+    /// Read the optional `urls` config section, returns None if link listing is not enabled
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if !config.get::<bool>("urls.enabled").unwrap_or(false) {
+            return None;
+        }
+        Some(UrlInspector {
+            blocklist: config.get::<Vec<String>>("urls.blocklist")
+                .unwrap_or_default()
+                .into_iter()
+                .map(|domain| domain.trim_start_matches('.').to_lowercase())
+                .collect(),
+            preview: config.get::<bool>("urls.preview").unwrap_or(false),
+        })
+    }
+
+    /// Render the links of a message as markdown list, or None if there are none
+    pub async fn describe(&self, client: &Client, body: &str, formatted_body: Option<&str>) -> Option<String> {
+        let urls = extract(body, formatted_body);
+        if urls.is_empty() {
+            return None;
+        }
+        let mut lines = Vec::new();
+        for url in urls.iter().take(MAX_LINKS) {
+            // Known bad links are defanged, so nobody opens them by accident, and the homeserver
+            // doesn't fetch them for a preview
+            if self.is_blocklisted(url) {
+                lines.push(format!("- {} ⚠️ **blocklisted domain**", defang(url)));
+                continue;
+            }
+            let mut line = format!("- {}", markdown::escape(url.as_str()));
+            if self.preview {
+                if let Some(preview) = fetch_preview(client, url).await {
+                    line.push_str(&format!(": {}", markdown::escape(&preview)));
+                }
+            }
+            lines.push(line);
+        }
+        if urls.len() > MAX_LINKS {
+            lines.push(format!("- and {} more links", urls.len() - MAX_LINKS));
+        }
+        Some(lines.join("\n"))
+    }
+
+    fn is_blocklisted(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str().map(str::to_lowercase) else {
+            return false;
+        };
+        self.blocklist.iter().any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+    }
+}
+
+/// Find http(s) links in the plain text body and link targets in the formatted body, in order
+/// of appearance and without duplicates. matrix.to links are left out, they are mentions or
+/// permalinks rather than external websites.
+fn extract(body: &str, formatted_body: Option<&str>) -> Vec<Url> {
+    let plain = body.split_whitespace().map(str::to_owned);
+    let hrefs = formatted_body.into_iter()
+        .flat_map(|html| html.split("href=").skip(1))
+        .filter_map(|attr| {
+            let quote = attr.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            attr[1..].split(quote).next().map(|href| href.replace("&amp;", "&"))
+        });
+    let mut urls: Vec<Url> = Vec::new();
+    for candidate in plain.chain(hrefs) {
+        let candidate = candidate.trim_matches(|c: char| "<>()[]{}\"',.;!?".contains(c));
+        let Ok(url) = Url::parse(candidate) else {
+            continue;
+        };
+        if !matches!(url.scheme(), "http" | "https") || url.host_str() == Some("matrix.to") {
+            continue;
+        }
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// Render a link so clients don't make it clickable, like `hxxps://evil[.]example/path`
+fn defang(url: &Url) -> String {
+    let scheme = url.scheme().replacen("http", "hxxp", 1);
+    let host = url.host_str().unwrap_or_default().replace('.', "[.]");
+    let rest = &url[Position::AfterHost..];
+    format!("`{scheme}://{host}{}`", rest.replace('`', "%60"))
+}
+
+async fn fetch_preview(client: &Client, url: &Url) -> Option<String> {
+    let request = get_media_preview::v1::Request::new(url.to_string());
+    let response = match client.send(request).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Failed to fetch URL preview for {url}: {e}");
+            return None;
+        }
+    };
+    let preview: Preview = serde_json::from_str(response.data?.get()).ok()?;
+    let text = match (preview.title, preview.description) {
+        (Some(title), Some(description)) => format!("{title} — {description}"),
+        (Some(text), None) | (None, Some(text)) => text,
+        (None, None) => return None,
+    };
+    Some(markdown::snippet(&text, PREVIEW_SNIPPET_LENGTH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(expected: &[&str]) -> Vec<Url> {
+        expected.iter().map(|url| Url::parse(url).unwrap()).collect()
+    }
+
+    #[test]
+    fn extracts_plain_links_without_punctuation() {
+        let body = "see (https://example.com/a), or https://example.org.";
+        assert_eq!(extract(body, None), urls(&["https://example.com/a", "https://example.org"]));
+    }
+
+    #[test]
+    fn extracts_hrefs_without_duplicates() {
+        let body = "https://example.com/?a=1&b=2 and this";
+        let formatted = r#"<a href="https://example.com/?a=1&amp;b=2">link</a> and <a href='https://example.net'>this</a>"#;
+        assert_eq!(
+            extract(body, Some(formatted)),
+            urls(&["https://example.com/?a=1&b=2", "https://example.net"]),
+        );
+    }
+
+    #[test]
+    fn skips_matrix_to_and_other_schemes() {
+        let body = "hey https://matrix.to/#/@user:example.com mailto:a@example.com ftp://example.com";
+        assert!(extract(body, None).is_empty());
+    }
+
+    #[test]
+    fn blocklist_matches_subdomains_ignoring_case() {
+        let inspector = UrlInspector { blocklist: vec![String::from("evil.example")], preview: false };
+        let blocklisted = |url: &str| inspector.is_blocklisted(&Url::parse(url).unwrap());
+        assert!(blocklisted("https://evil.example/login"));
+        assert!(blocklisted("https://Login.EVIL.example"));
+        assert!(!blocklisted("https://notevil.example"));
+        assert!(!blocklisted("https://evil.example.org"));
+    }
+
+    #[test]
+    fn defangs_links() {
+        let url = Url::parse("https://login.evil.example:8443/a.php?next=`x`").unwrap();
+        assert_eq!(defang(&url), "`hxxps://login[.]evil[.]example:8443/a.php?next=%60x%60`");
+    }
+}
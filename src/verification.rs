@@ -0,0 +1,121 @@
+use log::{error, info, warn};
+use matrix_sdk::{
+    Client,
+    encryption::verification::SasVerification,
+    event_handler::Ctx,
+    ruma::{
+        events::key::verification::{
+            VerificationMethod,
+            done::ToDeviceKeyVerificationDoneEvent,
+            key::ToDeviceKeyVerificationKeyEvent,
+            request::ToDeviceKeyVerificationRequestEvent,
+            start::ToDeviceKeyVerificationStartEvent,
+        },
+        UserId,
+    },
+};
+
+use crate::{notify_report_rooms, BotContext};
+
+// Emoji verification of the bot's device by users in bot.verification_allowlist. Since nobody
+// can look at the bot's screen, it confirms the emoji on its side right away and posts them to
+// the report rooms, the allowlisted user checks them in their own client.
+
+fn is_allowed(bot_context: &BotContext, user_id: &UserId) -> bool {
+    bot_context.verification_allowlist.iter().any(|allowed| allowed == user_id)
+}
+
+async fn sas(client: &Client, user_id: &UserId, flow_id: &str) -> Option<SasVerification> {
+    client.encryption().get_verification(user_id, flow_id).await?.sas()
+}
+
+pub async fn handle_request(
+    event: ToDeviceKeyVerificationRequestEvent,
+    client: Client,
+    bot_context: Ctx<BotContext>,
+) {
+    let sender = &event.sender;
+    let device = &event.content.from_device;
+    if !is_allowed(&bot_context, sender) {
+        warn!("Ignoring verification request from {sender} device {device}, not in allowlist");
+        let msg = format!(
+            "{sender} asked to verify my device from their device {device}, but they are not in \
+             bot.verification_allowlist, so I ignored it"
+        );
+        notify_report_rooms(&client, &bot_context, &msg, false).await;
+        return;
+    }
+    let Some(request) = client.encryption().get_verification_request(sender, &event.content.transaction_id).await else {
+        error!("Verification request from {sender} device {device} not found");
+        return;
+    };
+    info!("Accepting verification request from {sender} device {device}");
+    if let Err(e) = request.accept_with_methods(vec![VerificationMethod::SasV1]).await {
+        error!("Failed to accept verification request from {sender}: {e}");
+    }
+}
+
+pub async fn handle_start(
+    event: ToDeviceKeyVerificationStartEvent,
+    client: Client,
+    bot_context: Ctx<BotContext>,
+) {
+    if !is_allowed(&bot_context, &event.sender) {
+        return;
+    }
+    let Some(sas) = sas(&client, &event.sender, event.content.transaction_id.as_str()).await else {
+        return;
+    };
+    if let Err(e) = sas.accept().await {
+        error!("Failed to accept emoji verification with {}: {e}", event.sender);
+    }
+}
+
+pub async fn handle_key(
+    event: ToDeviceKeyVerificationKeyEvent,
+    client: Client,
+    bot_context: Ctx<BotContext>,
+) {
+    if !is_allowed(&bot_context, &event.sender) {
+        return;
+    }
+    let Some(sas) = sas(&client, &event.sender, event.content.transaction_id.as_str()).await else {
+        return;
+    };
+    let Some(emoji) = sas.emoji() else {
+        return;
+    };
+    let emoji = emoji
+        .iter()
+        .map(|emoji| format!("{} ({})", emoji.symbol, emoji.description))
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!("Confirming emoji verification with {}: {emoji}", event.sender);
+    let msg = format!(
+        "Verifying my device with {} device {}, they should see these emoji: {emoji}",
+        event.sender,
+        sas.other_device().device_id(),
+    );
+    notify_report_rooms(&client, &bot_context, &msg, false).await;
+    if let Err(e) = sas.confirm().await {
+        error!("Failed to confirm emoji verification with {}: {e}", event.sender);
+    }
+}
+
+pub async fn handle_done(
+    event: ToDeviceKeyVerificationDoneEvent,
+    client: Client,
+    bot_context: Ctx<BotContext>,
+) {
+    if !is_allowed(&bot_context, &event.sender) {
+        return;
+    }
+    let Some(sas) = sas(&client, &event.sender, event.content.transaction_id.as_str()).await else {
+        return;
+    };
+    if sas.is_done() {
+        info!("Verified with {} device {}", event.sender, sas.other_device().device_id());
+        let msg = format!("Verified my device with {} device {}", event.sender, sas.other_device().device_id());
+        notify_report_rooms(&client, &bot_context, &msg, false).await;
+    }
+}
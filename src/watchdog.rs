@@ -0,0 +1,80 @@
+use log::{info, warn};
+use matrix_sdk::{
+    Client, Room, RoomState,
+    ruma::{events::room::server_acl::RoomServerAclEventContent, OwnedRoomId, ServerName},
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{notify_report_rooms, BotContext};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Missing {
+    since: Instant,
+    alerted: bool,
+}
+
+/// Periodically check that the bot is still joined to all watched rooms and that its server isn't
+/// banned from them by a server ACL, and alert the report rooms once a watched room has been gone
+/// for longer than `threshold`
+pub async fn run(client: Client, bot_context: BotContext, threshold: Duration) {
+    let Some(own_server) = client.user_id().map(|user_id| user_id.server_name().to_owned()) else {
+        warn!("Not logged in, can't watch for missing watched rooms");
+        return;
+    };
+    let mut missing: HashMap<OwnedRoomId, Missing> = HashMap::new();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        for room_id in bot_context.watched_rooms.iter().chain(bot_context.watched_test_rooms.iter()) {
+            // A server ACL keeps our membership intact, but events from other servers stop
+            // arriving, so it needs to be checked separately
+            let problem = match &client.get_room(room_id) {
+                None => Some(String::from("it is unknown to my client")),
+                Some(room) if room.state() != RoomState::Joined => Some(format!("my membership is {:?}", room.state())),
+                Some(room) if acl_denies(room, &own_server).await => Some(format!("its server ACL denies my server {own_server}")),
+                Some(_) => None,
+            };
+            let Some(reason) = problem else {
+                if let Some(gone) = missing.remove(room_id) {
+                    info!("Watched room {room_id} is back");
+                    if gone.alerted {
+                        let msg = format!("Watched room {room_id} is back, I'm monitoring it again.");
+                        notify_report_rooms(&client, &bot_context, &msg, false).await;
+                    }
+                }
+                continue;
+            };
+            let gone = missing.entry(room_id.clone()).or_insert_with(|| {
+                warn!("Watched room {room_id} is not usable anymore: {reason}");
+                Missing { since: Instant::now(), alerted: false }
+            });
+            if !gone.alerted && gone.since.elapsed() >= threshold {
+                let msg = format!(
+                    "I have lost watched room {room_id} for {} minutes ({reason}). I can't report pings from there anymore!",
+                    gone.since.elapsed().as_secs() / 60,
+                );
+                // Losing a test room is not worth pinging everyone
+                let urgent = bot_context.watched_rooms.contains(room_id);
+                notify_report_rooms(&client, &bot_context, &msg, urgent).await;
+                gone.alerted = true;
+            }
+        }
+    }
+}
+
+/// Whether the room's m.room.server_acl denies `server`
+async fn acl_denies(room: &Room, server: &ServerName) -> bool {
+    let Ok(Some(raw)) = room.get_state_event_static::<RoomServerAclEventContent>().await else {
+        return false;
+    };
+    let Ok(event) = raw.deserialize() else {
+        return false;
+    };
+    event.as_sync()
+        .and_then(|event| event.as_original())
+        .is_some_and(|event| !event.content.is_allowed(server))
+}